@@ -0,0 +1,93 @@
+use crate::error::{Error, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs::OpenOptions, io::Write, path::Path};
+
+/// Name of the manifest the collector writes next to the chunks it copies
+pub const MANIFEST_FILE_NAME: &str = "manifest.jsonl";
+
+/// One recorded chunk, stored as a JSON line in the manifest
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    pub path: String,
+    /// RFC 3339 wall-clock time py-spy started recording the chunk
+    pub started_at: String,
+    pub duration_seconds: u16,
+}
+
+/// Appends an entry to the manifest in `dir`, creating it if needed
+pub fn append_entry(dir: &str, entry: &ManifestEntry) -> Result<()> {
+    let manifest_path = Path::new(dir).join(MANIFEST_FILE_NAME);
+    let manifest_path_str = manifest_path.display().to_string();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&manifest_path)
+        .map_err(|e| Error::io(&manifest_path_str, e))?;
+    let line = serde_json::to_string(entry).expect("manifest entries are always serializable");
+    writeln!(file, "{}", line).map_err(|e| Error::io(&manifest_path_str, e))
+}
+
+/// Reads a manifest into a map from chunk file name to the time it started recording.
+/// Entries are matched by file name so the manifest still applies after the
+/// chunks are moved to another directory.
+pub fn read_start_times(manifest_path: &str) -> Result<HashMap<String, DateTime<Utc>>> {
+    let content =
+        std::fs::read_to_string(manifest_path).map_err(|e| Error::io(manifest_path, e))?;
+    let mut start_times = HashMap::new();
+    for (number, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: ManifestEntry = serde_json::from_str(line).map_err(|e| {
+            Error::invalid_manifest(manifest_path, format!("line {}: {}", number + 1, e))
+        })?;
+        let started_at = DateTime::parse_from_rfc3339(&entry.started_at).map_err(|e| {
+            Error::invalid_manifest(
+                manifest_path,
+                format!(
+                    "line {}: bad started_at {:?} for {}: {}",
+                    number + 1,
+                    entry.started_at,
+                    entry.path,
+                    e
+                ),
+            )
+        })?;
+        start_times.insert(file_name(&entry.path), started_at.with_timezone(&Utc));
+    }
+    Ok(start_times)
+}
+
+pub fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_malformed_manifest_lines_are_reported_as_manifest_errors() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let manifest_path = temp_dir.path().join(MANIFEST_FILE_NAME);
+        let manifest_path = manifest_path.to_str().unwrap();
+        let entry = r#"{"path":"a.json","started_at":"yesterday","duration_seconds":1}"#;
+
+        for (content, expected) in [
+            ("\nnot json\n", "line 2: "),
+            (entry, "line 1: bad started_at \"yesterday\" for a.json: "),
+        ] {
+            std::fs::write(manifest_path, content).unwrap();
+
+            let error = read_start_times(manifest_path).unwrap_err();
+
+            assert!(matches!(error, Error::InvalidManifest { .. }));
+            let prefix = format!("invalid chunk manifest {}: {}", manifest_path, expected);
+            assert!(error.to_string().starts_with(&prefix), "{}", error);
+        }
+    }
+}
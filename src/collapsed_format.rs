@@ -0,0 +1,432 @@
+use crate::combine_speedscope::SCHEMA;
+use crate::speedscope_format::{
+    seconds_per_unit, Extra, Frame, FrameKey, Profile, SampledProfile, Shared, Speedscope,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, Write};
+
+/// What the number at the end of each collapsed line counts
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CollapsedValues {
+    /// Sum of the sample weights; time units are written as whole microseconds
+    #[default]
+    Weights,
+    /// Number of samples
+    Counts,
+}
+
+/// Options for writing folded stacks (`a;b;c 123`) as read by flamegraph.pl and inferno
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CollapsedOptions {
+    /// Start every stack with the name of its profile, so threads get their own tower
+    pub thread_names: bool,
+    /// Write frames as `name (file:line)` instead of `name (file)`
+    pub line_numbers: bool,
+    pub values: CollapsedValues,
+}
+
+/// Formats a frame the way py-spy's raw output does, `name (file:line)`
+pub fn frame_label(frame: &Frame, line_numbers: bool) -> String {
+    match (&frame.file, frame.line) {
+        (Some(file), Some(line)) if line_numbers => format!("{} ({}:{})", frame.name, file, line),
+        (Some(file), _) => format!("{} ({})", frame.name, file),
+        (None, _) => frame.name.clone(),
+    }
+}
+
+/// A label usable as one element of a folded stack, where `;` separates frames
+fn stack_element(label: &str) -> String {
+    label.replace(';', ":")
+}
+
+/// Writes every profile as folded stacks. Equal stacks are summed into one line,
+/// lines are sorted so the output is deterministic, and empty samples are left out.
+/// The speedscope file is expected to be valid, see `Speedscope::validate`.
+pub fn write_collapsed(
+    speedscope: &Speedscope,
+    options: &CollapsedOptions,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let labels: Vec<String> = speedscope
+        .shared
+        .frames
+        .iter()
+        .map(|frame| stack_element(&frame_label(frame, options.line_numbers)))
+        .collect();
+
+    let mut stacks: BTreeMap<String, f64> = BTreeMap::new();
+    for profile in &speedscope.profiles {
+        let profile = profile.to_sampled().map_err(io::Error::other)?;
+        // whole numbers keep the output readable by tools that only accept integers
+        let scale = seconds_per_unit(&profile.unit).map_or(1.0, |seconds| seconds * 1e6);
+        let thread_label = stack_element(&profile.name);
+        for (sample, weight) in profile.samples.iter().zip(&profile.weights) {
+            if sample.is_empty() {
+                continue;
+            }
+            let mut frames: Vec<&str> = Vec::with_capacity(sample.len() + 1);
+            if options.thread_names {
+                frames.push(&thread_label);
+            }
+            frames.extend(sample.iter().map(|index| labels[*index as usize].as_str()));
+            let value = match options.values {
+                CollapsedValues::Counts => 1.0,
+                CollapsedValues::Weights => weight * scale,
+            };
+            *stacks.entry(frames.join(";")).or_default() += value;
+        }
+    }
+
+    for (stack, value) in stacks {
+        let value = value.round() as u64;
+        if value > 0 {
+            writeln!(out, "{} {}", stack, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Options for reading folded stacks, such as py-spy's `--format raw` output
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CollapsedImportOptions {
+    /// Samples per second the stacks were recorded at, turning counts into seconds.
+    /// Without it values are kept as unit-less counts
+    pub sample_rate: Option<f64>,
+    /// The first frame of every stack is the thread (profile) name,
+    /// as written by `export --format collapsed --thread-names`
+    pub thread_names: bool,
+}
+
+/// Parses a frame label written as `name (file:line)`, `name (file)` or just `name`
+pub fn parse_frame_label(label: &str) -> Frame {
+    let parsed = label
+        .strip_suffix(')')
+        .and_then(|rest| rest.rsplit_once(" ("))
+        .filter(|(name, _)| !name.is_empty());
+    let Some((name, location)) = parsed else {
+        return Frame {
+            name: label.to_string(),
+            ..Frame::default()
+        };
+    };
+    let (file, line) = match location.rsplit_once(':') {
+        Some((file, line)) => match line.parse::<u32>() {
+            Ok(line) => (file, Some(line)),
+            Err(_) => (location, None),
+        },
+        None => (location, None),
+    };
+    Frame {
+        name: name.to_string(),
+        file: Some(file.to_string()),
+        line,
+        ..Frame::default()
+    }
+}
+
+/// Whether a frame is one of the process or thread frames py-spy puts at the root of
+/// stacks with `--threads` and `--subprocesses`
+fn is_py_spy_thread_frame(label: &str) -> bool {
+    label.starts_with("process ") || (label.starts_with("thread (") && label.ends_with(')'))
+}
+
+/// Builds a speedscope file from folded stacks, one line of `frame;frame;... value` each.
+///
+/// Frames are parsed into name, file and line so they intern like frames from speedscope
+/// files. Stacks are split into one profile per thread when they start with py-spy's
+/// thread frames or with `options.thread_names`, all other stacks go into a profile named
+/// `name`. Blank lines are skipped.
+pub fn read_collapsed(
+    reader: impl BufRead,
+    name: &str,
+    options: &CollapsedImportOptions,
+) -> Result<Speedscope, String> {
+    let mut frames = Vec::new();
+    let mut frame_indexes: HashMap<FrameKey, i32> = HashMap::new();
+    let mut profiles: Vec<SampledProfile> = Vec::new();
+    let mut profile_indexes: HashMap<String, usize> = HashMap::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (stack, value) = line
+            .rsplit_once(' ')
+            .ok_or_else(|| format!("line {} has no sample count: {:?}", i + 1, line))?;
+        let value: f64 = value
+            .parse()
+            .ok()
+            .filter(|value: &f64| value.is_finite() && *value >= 0.0)
+            .ok_or_else(|| format!("line {} has an invalid sample count {:?}", i + 1, value))?;
+
+        let mut labels: Vec<&str> = stack.split(';').collect();
+        let num_of_thread_frames = if options.thread_names {
+            1
+        } else {
+            labels
+                .iter()
+                .take_while(|label| is_py_spy_thread_frame(label))
+                .count()
+        };
+        let thread_frames: Vec<&str> = labels.drain(..num_of_thread_frames).collect();
+        let profile_name = if thread_frames.is_empty() {
+            name.to_string()
+        } else {
+            thread_frames.join(" ")
+        };
+
+        let sample = labels
+            .iter()
+            .map(|label| {
+                let frame = parse_frame_label(label);
+                *frame_indexes.entry(frame.key()).or_insert_with(|| {
+                    frames.push(frame);
+                    frames.len() as i32 - 1
+                })
+            })
+            .collect();
+        let index = *profile_indexes
+            .entry(profile_name)
+            .or_insert_with_key(|name| {
+                profiles.push(SampledProfile {
+                    name: name.clone(),
+                    unit: if options.sample_rate.is_some() {
+                        "seconds"
+                    } else {
+                        "none"
+                    }
+                    .to_string(),
+                    ..SampledProfile::default()
+                });
+                profiles.len() - 1
+            });
+        let weight = match options.sample_rate {
+            Some(sample_rate) => value / sample_rate,
+            None => value,
+        };
+        let profile = &mut profiles[index];
+        profile.samples.push(sample);
+        profile.weights.push(weight);
+        profile.end_value += weight;
+    }
+
+    Ok(Speedscope {
+        profiles: profiles.into_iter().map(Profile::Sampled).collect(),
+        shared: Shared {
+            frames,
+            extra: Extra::new(),
+        },
+        schema: SCHEMA.to_string(),
+        exporter: None,
+        name: Some(name.to_string()),
+        active_profile_index: None,
+        extra: Extra::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(name: &str, file: &str, line: u32) -> Frame {
+        Frame {
+            name: name.to_string(),
+            file: Some(file.to_string()),
+            line: Some(line),
+            ..Frame::default()
+        }
+    }
+
+    fn profile(name: &str, samples: Vec<Vec<i32>>, weights: Vec<f64>) -> Profile {
+        Profile::Sampled(SampledProfile {
+            name: name.to_string(),
+            unit: "seconds".to_string(),
+            end_value: weights.iter().sum(),
+            samples,
+            weights,
+            ..SampledProfile::default()
+        })
+    }
+
+    fn input() -> Speedscope {
+        Speedscope {
+            profiles: vec![
+                profile(
+                    "MainThread",
+                    vec![vec![0, 1], vec![0, 1], vec![], vec![0]],
+                    vec![0.01, 0.02, 0.5, 0.01],
+                ),
+                profile("worker", vec![vec![0, 1]], vec![0.01]),
+            ],
+            shared: Shared {
+                frames: vec![
+                    frame("<module>", "app.py", 1),
+                    frame("handle", "views.py", 12),
+                ],
+                ..Shared::default()
+            },
+            schema: String::new(),
+            exporter: None,
+            name: None,
+            active_profile_index: None,
+            extra: Default::default(),
+        }
+    }
+
+    fn collapsed(options: CollapsedOptions) -> String {
+        let mut out = Vec::new();
+        write_collapsed(&input(), &options, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_stacks_are_merged_across_threads_by_default() {
+        assert_eq!(
+            collapsed(CollapsedOptions::default()),
+            "<module> (app.py) 10000\n<module> (app.py);handle (views.py) 40000\n"
+        );
+    }
+
+    #[test]
+    fn test_thread_names_and_line_numbers() {
+        let options = CollapsedOptions {
+            thread_names: true,
+            line_numbers: true,
+            ..CollapsedOptions::default()
+        };
+
+        assert_eq!(
+            collapsed(options),
+            "MainThread;<module> (app.py:1) 10000\n\
+             MainThread;<module> (app.py:1);handle (views.py:12) 30000\n\
+             worker;<module> (app.py:1);handle (views.py:12) 10000\n"
+        );
+    }
+
+    #[test]
+    fn test_separators_in_thread_names_are_replaced() {
+        let mut input = input();
+        input.profiles.truncate(1);
+        input.profiles[0] = profile("pool;worker", vec![vec![0]], vec![0.01]);
+        let options = CollapsedOptions {
+            thread_names: true,
+            ..CollapsedOptions::default()
+        };
+        let mut out = Vec::new();
+
+        write_collapsed(&input, &options, &mut out).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "pool:worker;<module> (app.py) 10000\n"
+        );
+    }
+
+    #[test]
+    fn test_counts() {
+        let options = CollapsedOptions {
+            values: CollapsedValues::Counts,
+            ..CollapsedOptions::default()
+        };
+
+        assert_eq!(
+            collapsed(options),
+            "<module> (app.py) 1\n<module> (app.py);handle (views.py) 3\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_frame_labels() {
+        let frame = parse_frame_label("handle (/app/views.py:12)");
+        assert_eq!(frame.name, "handle");
+        assert_eq!(frame.file.as_deref(), Some("/app/views.py"));
+        assert_eq!(frame.line, Some(12));
+
+        let frame = parse_frame_label("<module> (app.py)");
+        assert_eq!(frame.name, "<module>");
+        assert_eq!(frame.file.as_deref(), Some("app.py"));
+        assert_eq!(frame.line, None);
+
+        let frame = parse_frame_label("wrapper (C:\\app\\views.py:7)");
+        assert_eq!(frame.file.as_deref(), Some("C:\\app\\views.py"));
+        assert_eq!(frame.line, Some(7));
+
+        assert_eq!(parse_frame_label("[native code]").file, None);
+        assert_eq!(parse_frame_label("f (x)").name, "f");
+        assert_eq!(parse_frame_label(" (x)").name, " (x)");
+    }
+
+    #[test]
+    fn test_read_py_spy_raw_output() {
+        let raw = "thread (0x7F4E1A2B3740);<module> (app.py:10);handle (views.py:12) 3\n\
+                   thread (0x7F4E1A2B3740);<module> (app.py:10) 1\n\
+                   \n\
+                   thread (0x7F4E0F7FE640);worker (jobs.py:4) 2\n";
+        let options = CollapsedImportOptions {
+            sample_rate: Some(100.0),
+            ..CollapsedImportOptions::default()
+        };
+
+        let speedscope = read_collapsed(raw.as_bytes(), "raw.txt", &options).unwrap();
+
+        assert!(speedscope.validate().is_ok());
+        assert_eq!(speedscope.shared.frames.len(), 3);
+        assert_eq!(
+            speedscope.shared.frames[1].key(),
+            frame("handle", "views.py", 12).key()
+        );
+        let profiles: Vec<&SampledProfile> = speedscope
+            .profiles
+            .iter()
+            .map(|profile| match profile {
+                Profile::Sampled(profile) => profile,
+                Profile::Evented(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(profiles[0].name, "thread (0x7F4E1A2B3740)");
+        assert_eq!(profiles[0].unit, "seconds");
+        assert_eq!(profiles[0].samples, vec![vec![0, 1], vec![0]]);
+        assert_eq!(profiles[0].weights, vec![0.03, 0.01]);
+        assert_eq!(profiles[1].samples, vec![vec![2]]);
+    }
+
+    #[test]
+    fn test_collapsed_round_trips_through_export() {
+        let options = CollapsedOptions {
+            thread_names: true,
+            line_numbers: true,
+            values: CollapsedValues::Counts,
+        };
+        let mut exported = Vec::new();
+        write_collapsed(&input(), &options, &mut exported).unwrap();
+
+        let imported = read_collapsed(
+            exported.as_slice(),
+            "exported.txt",
+            &CollapsedImportOptions {
+                thread_names: true,
+                ..CollapsedImportOptions::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(imported.profiles.len(), 2);
+        assert_eq!(imported.profiles[1].name(), "worker");
+        let keys: Vec<FrameKey> = imported.shared.frames.iter().map(Frame::key).collect();
+        let original: Vec<FrameKey> = input().shared.frames.iter().map(Frame::key).collect();
+        assert_eq!(keys, original);
+    }
+
+    #[test]
+    fn test_line_without_count_is_rejected() {
+        let result = read_collapsed(
+            "main (app.py:1)".as_bytes(),
+            "bad.txt",
+            &CollapsedImportOptions::default(),
+        );
+
+        assert!(result.unwrap_err().contains("line 1"));
+    }
+}
@@ -1,369 +1,2195 @@
-use crate::speedscope_format::{self, Frame, Profile, Shared, Speedscope};
-use std::{collections::HashMap, fs::File, hash::Hash, io::BufReader};
-
-fn combine_samples_from_multiple_profiles(profiles: &[&Profile]) -> Vec<Vec<i32>> {
-    let mut combined_samples = Vec::new();
-    profiles.iter().for_each(|profile| {
-        profile.samples.iter().for_each(|sample| {
-            combined_samples.push(sample.clone());
-        });
-    });
-    return combined_samples;
+use crate::chunk_manifest;
+use crate::collapsed_format::{self, CollapsedImportOptions};
+use crate::error::{Error, Result};
+use crate::file_io::{self, Compression};
+use crate::frame_normalization::FrameNormalization;
+use crate::pprof_format::{self, PprofImportOptions};
+use crate::profile_grouping::GroupBy;
+use crate::speedscope_format::{
+    self, seconds_per_unit, unit_conversion, Extra, Frame, FrameKey, Profile, SampledProfile,
+    Shared, SESSION_START,
+};
+use chrono::{DateTime, Utc};
+use rayon::prelude::*;
+use serde::de::DeserializeOwned;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufWriter, Read, Seek, SeekFrom, Write},
+};
+
+pub const SCHEMA: &str = "https://www.speedscope.app/file-format-schema.json";
+/// Name of the combined file when the inputs don't share one
+const NAME: &str = "py-spy profile";
+
+/// Order of the profiles in the combined file
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProfileOrder {
+    /// In the order profiles are first seen in the inputs
+    #[default]
+    FirstSeen,
+    /// Sorted by profile name
+    Name,
 }
 
-fn combine_profiles_weights(profiles: &[&Profile]) -> Vec<f64> {
-    let mut combined_weights = Vec::new();
-    profiles.iter().for_each(|profile| {
-        let mut new_weights = Vec::new();
+/// Time unit of the combined profiles
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeUnit {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+}
 
-        profile.weights.iter().for_each(|weight| {
-            new_weights.push(*weight);
-        });
-        combined_weights.extend(new_weights);
-    });
-    return combined_weights;
+impl TimeUnit {
+    /// Name of the unit in speedscope files
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimeUnit::Nanoseconds => "nanoseconds",
+            TimeUnit::Microseconds => "microseconds",
+            TimeUnit::Milliseconds => "milliseconds",
+            TimeUnit::Seconds => "seconds",
+        }
+    }
 }
 
-pub fn extract_same_profile_groups(
-    speedscopes: &Vec<Speedscope>,
-) -> HashMap<String, Vec<&Profile>> {
-    return speedscopes
-        .iter()
-        .fold(HashMap::new(), |mut acc, speedscope| {
-            speedscope.profiles.iter().for_each(|profile| {
-                let name = profile.name.clone();
-                if !acc.contains_key(&name) {
-                    acc.insert(name.clone(), vec![profile]);
-                } else {
-                    let profiles = acc.get_mut(&name).unwrap();
-                    profiles.push(profile);
-                }
-            });
-            return acc;
-        });
+/// Options for combining speedscope files
+#[derive(Debug, Default)]
+pub struct CombineConfig {
+    /// Number of threads used to parse and remap inputs, defaults to the number of cores
+    pub jobs: Option<usize>,
+    pub profile_order: ProfileOrder,
+    /// Leave out inputs that can't be read, fail validation or are in a unit that can't be
+    /// merged with the profiles they belong to, instead of failing
+    pub skip_invalid: bool,
+    /// Combine chunks in the order they were recorded, spanning the recorded session
+    pub timeline: Option<TimelineConfig>,
+    /// Convert every profile to this unit, by default merged profiles keep the unit
+    /// they are first seen with
+    pub unit: Option<TimeUnit>,
+    /// Which profiles across inputs are merged into one
+    pub group_by: GroupBy,
+    /// Rules applied to frames before they are interned
+    pub normalization: FrameNormalization,
+    /// Replace the output file if it already exists
+    pub force: bool,
+    /// How inputs that are not speedscope files are read
+    pub import: ImportOptions,
 }
 
-fn create_hash_to_new_index_and_frame(
-    speedscopes: &[speedscope_format::Speedscope],
-) -> HashMap<String, (i32, speedscope_format::Frame)> {
-    let mut hash_to_new_index_and_frame = std::collections::HashMap::new();
-    let mut index = 0;
-    for speedscope in speedscopes {
-        speedscope.shared.frames.iter().for_each(|frame| {
-            let hash = frame.hash();
-            if !hash_to_new_index_and_frame.contains_key(&hash) {
-                hash_to_new_index_and_frame.insert(hash, (index.clone(), frame.clone()));
-                index += 1;
-            }
-        });
+/// How inputs in other formats than speedscope are read
+#[derive(Debug, Default, Clone)]
+pub struct ImportOptions {
+    pub collapsed: CollapsedImportOptions,
+    pub pprof: PprofImportOptions,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct TimelineConfig {
+    /// Manifest written by the collector with the start time of each chunk.
+    /// Chunks missing from it are placed by their file modification time,
+    /// which is roughly when they finished recording.
+    pub manifest_path: Option<String>,
+    /// Fill the time between chunks with empty samples so the time axis matches reality
+    pub insert_gaps: bool,
+}
+
+/// An input file ready to be combined
+pub struct CombineInput {
+    /// Only used for error messages
+    pub path: String,
+    pub speedscope: speedscope_format::Speedscope,
+    /// Wall-clock time the chunk started recording, required in timeline mode
+    pub started_at: Option<DateTime<Utc>>,
+}
+
+/// Outcome of a successful combine
+#[derive(Debug)]
+pub struct CombineSummary {
+    pub num_of_inputs: usize,
+    /// Inputs left out because of `CombineConfig::skip_invalid`, with the reason
+    pub skipped: Vec<Error>,
+}
+
+/// Every distinct frame seen so far, in the order it was first seen.
+/// The position of a frame in `frames` is its index in the combined file.
+#[derive(Default)]
+pub struct FrameTable {
+    key_to_new_index: HashMap<FrameKey, i32>,
+    frames: Vec<Frame>,
+}
+
+impl FrameTable {
+    pub fn intern(&mut self, key: FrameKey, frame: &Frame) -> i32 {
+        if let Some(index) = self.key_to_new_index.get(&key) {
+            return *index;
+        }
+        let index = self.frames.len() as i32;
+        self.key_to_new_index.insert(key, index);
+        self.frames.push(frame.clone());
+        index
+    }
+
+    pub fn into_frames(self) -> Vec<Frame> {
+        self.frames
     }
-    return hash_to_new_index_and_frame;
 }
 
-fn get_sorted_frames(hash_to_new_index_and_frame: &HashMap<String, (i32, Frame)>) -> Vec<Frame> {
-    // Convert HashMap into a vector of (i32, Frame) tuples
-    let mut sorted_vec: Vec<(i32, Frame)> = hash_to_new_index_and_frame
-        .values()
-        .cloned() // Clone the Frame objects to avoid borrowing issues
-        .collect();
+/// A value of the input files that is kept in the combined file only if all inputs agree on it
+#[derive(Default)]
+enum Common {
+    #[default]
+    Unseen,
+    Same(Option<String>),
+    Mixed,
+}
 
-    // Sort by the i32 value
-    sorted_vec.sort_by_key(|(index, _frame)| *index);
+impl Common {
+    fn see(&mut self, value: &Option<String>) {
+        match self {
+            Common::Unseen => *self = Common::Same(value.clone()),
+            Common::Same(same) if same != value => *self = Common::Mixed,
+            _ => {}
+        }
+    }
 
-    // Extract and collect the Frame objects into a Vec<Frame>
-    sorted_vec.into_iter().map(|(_, frame)| frame).collect()
+    fn value(self) -> Option<Option<String>> {
+        match self {
+            Common::Same(value) => Some(value),
+            _ => None,
+        }
+    }
 }
 
-fn adjust_speedscope_to_new_indexes_and_frames(
-    speedscope: &speedscope_format::Speedscope,
-    hash_to_new_index_and_frame: &HashMap<String, (i32, speedscope_format::Frame)>,
-) -> speedscope_format::Speedscope {
-    let mut new_profiles = vec![];
-    let new_shared_frames = get_sorted_frames(hash_to_new_index_and_frame);
+/// Bytes `offset..offset + len` of the spill file
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    offset: u64,
+    len: u64,
+}
 
-    for profile in &speedscope.profiles {
-        let mut new_samples = vec![];
-        // for each sample, create a list with the relevant new indexes
-        for sample in &profile.samples {
-            let mut new_sample = vec![];
-            for old_index in sample {
-                let (new_index, _) = hash_to_new_index_and_frame
-                    .get(&speedscope.shared.frames[*old_index as usize].hash())
-                    .unwrap();
-                new_sample.push(new_index.clone());
-            }
-            new_samples.push(new_sample);
+/// Temporary file the samples and weights of every combined profile are appended to.
+/// It is shared by all profiles so the number of open files doesn't grow with them.
+struct SpillFile {
+    writer: BufWriter<File>,
+    len: u64,
+}
+
+impl SpillFile {
+    fn new() -> io::Result<Self> {
+        Ok(Self {
+            writer: BufWriter::new(tempfile::tempfile()?),
+            len: 0,
+        })
+    }
+
+    /// Appends already serialized, comma separated JSON array elements to an array that
+    /// has `existing` elements in `blocks`, recording where they were written
+    fn append_elements(
+        &mut self,
+        blocks: &mut Vec<Block>,
+        existing: usize,
+        elements: &[u8],
+        num_of_elements: usize,
+    ) -> io::Result<()> {
+        let offset = self.len;
+        if existing > 0 && num_of_elements > 0 {
+            self.writer.write_all(b",")?;
+            self.len += 1;
+        }
+        self.writer.write_all(elements)?;
+        self.len += elements.len() as u64;
+        match blocks.last_mut() {
+            // consecutive appends to the same array are read back in one go
+            Some(last) if last.offset + last.len == offset => last.len += self.len - offset,
+            _ if self.len > offset => blocks.push(Block {
+                offset,
+                len: self.len - offset,
+            }),
+            _ => {}
         }
+        Ok(())
+    }
 
-        new_profiles.push(speedscope_format::Profile {
+    fn into_file(self) -> io::Result<File> {
+        self.writer.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+/// Copies the blocks of one array from the spill file
+fn copy_blocks(file: &mut File, blocks: &[Block], out: &mut impl Write) -> io::Result<()> {
+    for block in blocks {
+        file.seek(SeekFrom::Start(block.offset))?;
+        io::copy(&mut Read::by_ref(file).take(block.len), out)?;
+    }
+    Ok(())
+}
+
+/// Parses the blocks of one array from the spill file
+fn read_blocks<T: DeserializeOwned>(file: &mut File, blocks: &[Block]) -> io::Result<Vec<T>> {
+    let mut json = vec![b'['];
+    copy_blocks(file, blocks, &mut json)?;
+    json.push(b']');
+    Ok(serde_json::from_slice(&json)?)
+}
+
+/// Samples and weights of one combined profile.
+/// They are appended to the spill file as inputs arrive, already serialized as
+/// JSON array elements, so only the per profile metadata is kept in memory.
+struct ProfileSpill {
+    name: String,
+    unit: String,
+    start_value: f64,
+    end_value: f64,
+    samples: Vec<Block>,
+    num_of_samples: usize,
+    weights: Vec<Block>,
+    num_of_weights: usize,
+    extra: Extra,
+    /// Time at the end of the last sample, only tracked in timeline mode
+    cursor: f64,
+}
+
+impl ProfileSpill {
+    fn new(profile: &RemappedProfile, offset: f64) -> Self {
+        Self {
             name: profile.name.clone(),
             unit: profile.unit.clone(),
-            start_value: profile.start_value,
-            end_value: profile.end_value,
-            samples: new_samples,
-            weights: profile.weights.clone(),
-            r#type: profile.r#type.clone(),
-        });
+            start_value: offset + profile.start_value,
+            end_value: 0.0,
+            samples: Vec::new(),
+            num_of_samples: 0,
+            weights: Vec::new(),
+            num_of_weights: 0,
+            extra: profile.extra.clone(),
+            cursor: offset + profile.start_value,
+        }
+    }
+
+    /// Appends a profile right after the previous one, extending the profile by its duration
+    fn append(&mut self, spill: &mut SpillFile, remapped: &RemappedProfile) -> io::Result<()> {
+        self.append_elements(spill, remapped)?;
+        self.end_value += remapped.end_value;
+        Ok(())
+    }
+
+    /// Appends a profile that started `offset` units after the start of the session,
+    /// optionally preceded by an empty sample covering the time since the previous one
+    fn append_at(
+        &mut self,
+        spill: &mut SpillFile,
+        remapped: &RemappedProfile,
+        offset: f64,
+        insert_gaps: bool,
+    ) -> io::Result<()> {
+        let start = offset + remapped.start_value;
+        if insert_gaps && start > self.cursor {
+            spill.append_elements(&mut self.samples, self.num_of_samples, b"[]", 1)?;
+            self.num_of_samples += 1;
+            let gap = serde_json::to_vec(&(start - self.cursor))?;
+            spill.append_elements(&mut self.weights, self.num_of_weights, &gap, 1)?;
+            self.num_of_weights += 1;
+            self.cursor = start;
+        }
+        self.append_elements(spill, remapped)?;
+        self.cursor += remapped.total_weight;
+        self.end_value = self.end_value.max(offset + remapped.end_value);
+        Ok(())
+    }
+
+    fn append_elements(
+        &mut self,
+        spill: &mut SpillFile,
+        remapped: &RemappedProfile,
+    ) -> io::Result<()> {
+        spill.append_elements(
+            &mut self.samples,
+            self.num_of_samples,
+            &remapped.samples_json,
+            remapped.num_of_samples,
+        )?;
+        self.num_of_samples += remapped.num_of_samples;
+        spill.append_elements(
+            &mut self.weights,
+            self.num_of_weights,
+            &remapped.weights_json,
+            remapped.num_of_weights,
+        )?;
+        self.num_of_weights += remapped.num_of_weights;
+        Ok(())
+    }
+
+    /// Reads the profile back from the spill file
+    fn into_profile(self, spill: &mut File) -> io::Result<SampledProfile> {
+        Ok(SampledProfile {
+            samples: read_blocks(spill, &self.samples)?,
+            weights: read_blocks(spill, &self.weights)?,
+            name: self.name,
+            unit: self.unit,
+            start_value: self.start_value,
+            end_value: self.end_value,
+            extra: self.extra,
+        })
+    }
+
+    /// Writes the profile as a speedscope sampled profile object
+    fn write_to(self, spill: &mut File, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(b"{\"type\":\"sampled\",\"name\":")?;
+        serde_json::to_writer(&mut *out, &self.name)?;
+        out.write_all(b",\"unit\":")?;
+        serde_json::to_writer(&mut *out, &self.unit)?;
+        out.write_all(b",\"startValue\":")?;
+        serde_json::to_writer(&mut *out, &self.start_value)?;
+        out.write_all(b",\"endValue\":")?;
+        serde_json::to_writer(&mut *out, &self.end_value)?;
+        out.write_all(b",\"samples\":[")?;
+        copy_blocks(spill, &self.samples, out)?;
+        out.write_all(b"],\"weights\":[")?;
+        copy_blocks(spill, &self.weights, out)?;
+        out.write_all(b"]")?;
+        write_extra(&self.extra, out)?;
+        out.write_all(b"}")
     }
-    let new_shared = speedscope_format::Shared {
-        frames: new_shared_frames,
-    };
-    return speedscope_format::Speedscope {
-        profiles: new_profiles,
-        shared: new_shared,
-        schema: speedscope.schema.clone(),
-        exporter: speedscope.exporter.clone(),
-        name: speedscope.name.clone(),
-    };
 }
 
-fn calculate_end_value(profiles: &[&Profile]) -> f64 {
-    let mut end_value = 0.0;
-    profiles.iter().for_each(|profile| {
-        end_value += profile.end_value;
-    });
-    return end_value;
+/// Writes extra fields the way `#[serde(flatten)]` does, after the other fields of an object
+fn write_extra(extra: &Extra, out: &mut impl Write) -> io::Result<()> {
+    for (key, value) in extra {
+        out.write_all(b",")?;
+        serde_json::to_writer(&mut *out, key)?;
+        out.write_all(b":")?;
+        serde_json::to_writer(&mut *out, value)?;
+    }
+    Ok(())
 }
 
-pub fn read_speedscope_files(paths: Vec<&str>) -> Vec<speedscope_format::Speedscope> {
-    // let mut frames = Vec::new();
-    let speedscopes = paths
-        .iter()
-        .map(|path| {
-            let file = File::open(path).unwrap();
-            let reader = BufReader::new(file);
-            serde_json::from_reader(reader).unwrap()
+/// One profile of an input, remapped to the shared frame table and serialized
+/// as JSON array elements ready to be appended to a `ProfileSpill`
+struct RemappedProfile {
+    name: String,
+    unit: String,
+    start_value: f64,
+    end_value: f64,
+    samples_json: Vec<u8>,
+    num_of_samples: usize,
+    weights_json: Vec<u8>,
+    num_of_weights: usize,
+    total_weight: f64,
+    extra: Extra,
+}
+
+/// Profile an input profile is merged into, and how to convert its values to that profile's unit
+struct MergeTarget {
+    name: String,
+    unit: String,
+    factor: f64,
+    /// Start of the input relative to the session in `unit`, only in timeline mode
+    offset: Option<f64>,
+}
+
+fn remap_profile(
+    path: &str,
+    profile: &SampledProfile,
+    new_indexes: &[i32],
+    target: &MergeTarget,
+) -> Result<RemappedProfile> {
+    let mut samples_json = Vec::new();
+    let mut new_sample = Vec::new();
+    for (i, sample) in profile.samples.iter().enumerate() {
+        new_sample.clear();
+        for old_index in sample {
+            let new_index = usize::try_from(*old_index)
+                .ok()
+                .and_then(|index| new_indexes.get(index))
+                .ok_or_else(|| {
+                    Error::invalid_profile(
+                        path,
+                        format!(
+                            "profile {} references frame {} but only {} frames exist",
+                            profile.name,
+                            old_index,
+                            new_indexes.len()
+                        ),
+                    )
+                })?;
+            new_sample.push(*new_index);
+        }
+        if i > 0 {
+            samples_json.push(b',');
+        }
+        serde_json::to_writer(&mut samples_json, &new_sample)
+            .expect("writing to a Vec never fails");
+    }
+    let mut weights_json = Vec::new();
+    let mut total_weight = 0.0;
+    for (i, weight) in profile.weights.iter().enumerate() {
+        if i > 0 {
+            weights_json.push(b',');
+        }
+        let weight = weight * target.factor;
+        total_weight += weight;
+        serde_json::to_writer(&mut weights_json, &weight).expect("writing to a Vec never fails");
+    }
+    Ok(RemappedProfile {
+        name: target.name.clone(),
+        unit: target.unit.clone(),
+        start_value: profile.start_value * target.factor,
+        end_value: profile.end_value * target.factor,
+        samples_json,
+        num_of_samples: profile.samples.len(),
+        weights_json,
+        num_of_weights: profile.weights.len(),
+        total_weight,
+        extra: profile.extra.clone(),
+    })
+}
+
+/// Combines speedscope files in batches.
+///
+/// Frames are interned into a shared table as each batch is added and its samples are
+/// remapped to the new indexes and spilled to disk right away, so peak memory is roughly
+/// the frame table plus the inputs of the batch currently being added.
+/// Key extraction and remapping of the inputs of a batch run in parallel, interning and
+/// appending always happen in input order so the output does not depend on the thread count.
+/// Frames are normalized with `with_normalization` before they are interned.
+/// Profiles with the same name across inputs, or the same group with `with_group_by`,
+/// are merged into one profile, kept in the order they were first seen. Evented profiles are converted to sampled ones.
+/// The exporter and name of the inputs are kept if all inputs agree on them, and the
+/// active profile of the first input that has one stays the active profile.
+///
+/// By default each input is appended right after the previous one. In timeline mode each
+/// profile starts and ends at the wall-clock time of its inputs relative to the first input,
+/// which must come first. Samples stay back to back unless gaps are inserted between them.
+#[derive(Default)]
+pub struct StreamingCombiner {
+    frames: FrameTable,
+    name_to_profile_index: HashMap<String, usize>,
+    profiles: Vec<ProfileSpill>,
+    /// Created when the first profile is added
+    spill: Option<SpillFile>,
+    num_of_inputs: usize,
+    exporter: Common,
+    name: Common,
+    active_profile_name: Option<String>,
+    timeline: Option<Timeline>,
+    /// Unit every profile is converted to, if the user picked one
+    unit: Option<TimeUnit>,
+    /// Unit of each merged profile, decided when its name is first seen
+    name_to_unit: HashMap<String, String>,
+    group_by: GroupBy,
+    normalization: FrameNormalization,
+    skip_invalid: bool,
+}
+
+struct Timeline {
+    insert_gaps: bool,
+    /// Earliest start of all inputs, where the timeline begins
+    session_start: DateTime<Utc>,
+}
+
+impl StreamingCombiner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Places inputs by their start time relative to `session_start`, which must not be
+    /// later than the start of any input
+    pub fn with_timeline(insert_gaps: bool, session_start: DateTime<Utc>) -> Self {
+        Self {
+            timeline: Some(Timeline {
+                insert_gaps,
+                session_start,
+            }),
+            ..Self::default()
+        }
+    }
+
+    /// Converts every profile to `unit` instead of the unit it is first seen with
+    pub fn with_unit(mut self, unit: Option<TimeUnit>) -> Self {
+        self.unit = unit;
+        self
+    }
+
+    /// Merges profiles by `group_by` instead of by exact name
+    pub fn with_group_by(mut self, group_by: GroupBy) -> Self {
+        self.group_by = group_by;
+        self
+    }
+
+    /// Normalizes frames before interning them, so frames equal after normalization are merged
+    pub fn with_normalization(mut self, normalization: FrameNormalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    /// Leaves out inputs whose profiles can't be merged instead of failing the batch
+    pub fn with_skip_invalid(mut self, skip_invalid: bool) -> Self {
+        self.skip_invalid = skip_invalid;
+        self
+    }
+
+    /// Decides which profile every profile of an input is merged into, the unit it is merged
+    /// in, how to convert its values to it and, in timeline mode, where it starts.
+    /// Time units convert into each other, any other mismatch is an error.
+    /// Units of new profiles are only remembered when the whole input is accepted,
+    /// so a rejected input leaves no trace.
+    fn merge_targets(&mut self, input: &CombineInput) -> Result<Vec<MergeTarget>> {
+        let mut new_units: HashMap<String, String> = HashMap::new();
+        let mut targets = Vec::with_capacity(input.speedscope.profiles.len());
+        for profile in &input.speedscope.profiles {
+            let name = self.group_by.group_name(&input.path, profile.name());
+            let unit = profile.unit();
+            let target = match (self.unit, self.name_to_unit.get(&name)) {
+                (Some(target), _) => target.as_str().to_string(),
+                (None, Some(target)) => target.clone(),
+                (None, None) => new_units
+                    .entry(name.clone())
+                    .or_insert_with(|| unit.to_string())
+                    .clone(),
+            };
+            let factor = unit_conversion(unit, &target).ok_or_else(|| {
+                Error::invalid_profile(
+                    &input.path,
+                    format!(
+                        "profile {} is in {:?} which can't be converted to {:?} of {}",
+                        profile.name(),
+                        unit,
+                        target,
+                        name
+                    ),
+                )
+            })?;
+            let offset = self.timeline_offset(input, &target)?;
+            targets.push(MergeTarget {
+                name,
+                unit: target,
+                factor,
+                offset,
+            });
+        }
+        self.name_to_unit.extend(new_units);
+        Ok(targets)
+    }
+
+    /// Offset of an input from the session start in units of `unit`, `None` when not in
+    /// timeline mode
+    fn timeline_offset(&self, input: &CombineInput, unit: &str) -> Result<Option<f64>> {
+        let Some(timeline) = &self.timeline else {
+            return Ok(None);
+        };
+        let started_at = input.started_at.ok_or_else(|| {
+            Error::invalid_profile(&input.path, "no recording start time for timeline mode")
+        })?;
+        if started_at < timeline.session_start {
+            return Err(Error::invalid_profile(
+                &input.path,
+                format!(
+                    "started at {} before the session start {}",
+                    started_at.to_rfc3339(),
+                    timeline.session_start.to_rfc3339()
+                ),
+            ));
+        }
+        let seconds_per_unit = seconds_per_unit(unit).ok_or_else(|| {
+            Error::invalid_profile(
+                &input.path,
+                format!("unit {:?} can't be placed on a timeline", unit),
+            )
+        })?;
+        let offset_micros = (started_at - timeline.session_start)
+            .num_microseconds()
+            .expect("chunks of one session are less than 292 thousand years apart");
+        let offset_seconds = offset_micros as f64 * 1e-6;
+        Ok(Some(offset_seconds / seconds_per_unit))
+    }
+
+    /// Adds parsed input files, in order, using the current rayon thread pool.
+    /// Where each input goes is decided before any of its frames are interned. Inputs that
+    /// can't be merged fail the batch, or with `with_skip_invalid` are left out and returned.
+    pub fn add_batch(&mut self, inputs: Vec<CombineInput>) -> Result<Vec<Error>> {
+        let mut skipped = Vec::new();
+        let mut accepted = Vec::with_capacity(inputs.len());
+        let mut targets: Vec<Vec<MergeTarget>> = Vec::with_capacity(inputs.len());
+        for input in inputs {
+            match self.merge_targets(&input) {
+                Ok(input_targets) => {
+                    accepted.push(input);
+                    targets.push(input_targets);
+                }
+                Err(e) if self.skip_invalid => skipped.push(e),
+                Err(e) => return Err(e),
+            }
+        }
+        let inputs = accepted;
+
+        let normalized: Vec<Vec<(FrameKey, Cow<Frame>)>> = inputs
+            .par_iter()
+            .map(|input| {
+                input
+                    .speedscope
+                    .shared
+                    .frames
+                    .iter()
+                    .map(|frame| {
+                        let frame = self.normalization.apply(frame);
+                        (frame.key(), frame)
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let new_indexes: Vec<Vec<i32>> = normalized
+            .into_iter()
+            .map(|frames| {
+                frames
+                    .into_iter()
+                    .map(|(key, frame)| self.frames.intern(key, &frame))
+                    .collect()
+            })
+            .collect();
+
+        let remapped: Vec<Result<Vec<RemappedProfile>>> = inputs
+            .par_iter()
+            .zip(new_indexes.par_iter())
+            .zip(targets.par_iter())
+            .map(
+                |(
+                    (
+                        CombineInput {
+                            path, speedscope, ..
+                        },
+                        new_indexes,
+                    ),
+                    targets,
+                )| {
+                    speedscope
+                        .profiles
+                        .iter()
+                        .zip(targets)
+                        .map(|(profile, target)| {
+                            let profile = profile
+                                .to_sampled()
+                                .map_err(|reason| Error::invalid_profile(path, reason))?;
+                            remap_profile(path, &profile, new_indexes, target)
+                        })
+                        .collect()
+                },
+            )
+            .collect();
+
+        for ((input, profiles), targets) in inputs.iter().zip(remapped).zip(&targets) {
+            let (path, speedscope) = (&input.path, &input.speedscope);
+            let profiles = profiles?;
+            self.exporter.see(&speedscope.exporter);
+            self.name.see(&speedscope.name);
+            if self.active_profile_name.is_none() {
+                self.active_profile_name = speedscope
+                    .active_profile_index
+                    .and_then(|index| speedscope.profiles.get(index))
+                    .map(|profile| self.group_by.group_name(path, profile.name()));
+            }
+            for (profile, target) in profiles.into_iter().zip(targets) {
+                let offset = target.offset;
+                let spill_file = match &mut self.spill {
+                    Some(spill_file) => spill_file,
+                    None => self
+                        .spill
+                        .insert(SpillFile::new().map_err(|e| Error::io(path, e))?),
+                };
+                let index = match self.name_to_profile_index.get(&profile.name) {
+                    Some(index) => *index,
+                    None => {
+                        let spill = ProfileSpill::new(&profile, offset.unwrap_or(0.0));
+                        self.profiles.push(spill);
+                        self.name_to_profile_index
+                            .insert(profile.name.clone(), self.profiles.len() - 1);
+                        self.profiles.len() - 1
+                    }
+                };
+                let spill = &mut self.profiles[index];
+                match (offset, &self.timeline) {
+                    (Some(offset), Some(timeline)) => {
+                        spill.append_at(spill_file, &profile, offset, timeline.insert_gaps)
+                    }
+                    _ => spill.append(spill_file, &profile),
+                }
+                .map_err(|e| Error::io(path, e))?;
+            }
+            self.num_of_inputs += 1;
+        }
+        Ok(skipped)
+    }
+
+    pub fn num_of_inputs(&self) -> usize {
+        self.num_of_inputs
+    }
+
+    /// Start of the timeline as written to the combined file, `None` when not in timeline mode
+    fn session_start(&self) -> Option<String> {
+        self.timeline
+            .as_ref()
+            .map(|timeline| timeline.session_start.to_rfc3339())
+    }
+
+    /// Puts the profiles in `profile_order`, returning the index of the active profile
+    fn order_profiles(&mut self, profile_order: ProfileOrder) -> Option<usize> {
+        if profile_order == ProfileOrder::Name {
+            // stable, so profiles with equal names keep their first seen order
+            self.profiles.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+        self.active_profile_name.as_ref().and_then(|name| {
+            self.profiles
+                .iter()
+                .position(|profile| &profile.name == name)
+        })
+    }
+
+    /// Returns the combined speedscope file, reading the spilled samples back into memory.
+    /// It is equal to what `finish` writes.
+    pub fn into_speedscope(
+        mut self,
+        profile_order: ProfileOrder,
+    ) -> io::Result<speedscope_format::Speedscope> {
+        let active_profile_index = self.order_profiles(profile_order);
+        let mut extra = Extra::new();
+        if let Some(session_start) = self.session_start() {
+            extra.insert(SESSION_START.to_string(), session_start.into());
+        }
+        let mut spill_file = self.spill.map(SpillFile::into_file).transpose()?;
+        let profiles = self
+            .profiles
+            .into_iter()
+            .map(|spill| {
+                let spill_file = spill_file
+                    .as_mut()
+                    .expect("the spill file is created with the first profile");
+                spill.into_profile(spill_file).map(Profile::Sampled)
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(speedscope_format::Speedscope {
+            profiles,
+            shared: Shared {
+                frames: self.frames.frames,
+                extra: Extra::new(),
+            },
+            schema: SCHEMA.to_string(),
+            exporter: self.exporter.value().flatten(),
+            name: self.name.value().unwrap_or(Some(NAME.to_string())),
+            active_profile_index,
+            extra,
         })
-        .collect();
-    return speedscopes;
-}
-
-pub fn combine_speedscope_files(
-    adjusted_speedscopes: Vec<speedscope_format::Speedscope>,
-    hash_to_new_index_and_frame: HashMap<String, (i32, speedscope_format::Frame)>,
-) -> speedscope_format::Speedscope {
-    let profile_groups = extract_same_profile_groups(&adjusted_speedscopes);
-    let mut new_profiles = Vec::new();
-    let new_shared_frames = get_sorted_frames(&hash_to_new_index_and_frame);
-    for (name, profiles) in profile_groups {
-        let combined_weights = combine_profiles_weights(profiles.as_slice());
-        let combined_samples = combine_samples_from_multiple_profiles(profiles.as_slice());
-        let start_value = profiles[0].start_value;
-        let end_value = calculate_end_value(profiles.as_slice());
-        new_profiles.push(Profile {
-            name: name.clone(),
-            unit: profiles[0].unit.clone(),
-            start_value: start_value,
-            end_value: end_value,
-            samples: combined_samples,
-            weights: combined_weights,
-            r#type: "sampled".to_string(),
+    }
+
+    /// Writes the combined speedscope file.
+    /// The output is the same JSON `serde_json` would produce for the equivalent `Speedscope`.
+    pub fn finish(mut self, profile_order: ProfileOrder, out: &mut impl Write) -> io::Result<()> {
+        let active_profile_index = self.order_profiles(profile_order);
+        let session_start = self.session_start();
+        let mut spill_file = self.spill.map(SpillFile::into_file).transpose()?;
+        out.write_all(b"{\"profiles\":[")?;
+        for (i, spill) in self.profiles.into_iter().enumerate() {
+            if i > 0 {
+                out.write_all(b",")?;
+            }
+            let spill_file = spill_file
+                .as_mut()
+                .expect("the spill file is created with the first profile");
+            spill.write_to(spill_file, out)?;
+        }
+        out.write_all(b"],\"shared\":")?;
+        serde_json::to_writer(
+            &mut *out,
+            &Shared {
+                frames: self.frames.frames,
+                extra: Extra::new(),
+            },
+        )?;
+        out.write_all(b",\"$schema\":")?;
+        serde_json::to_writer(&mut *out, SCHEMA)?;
+        if let Some(exporter) = self.exporter.value().flatten() {
+            out.write_all(b",\"exporter\":")?;
+            serde_json::to_writer(&mut *out, &exporter)?;
+        }
+        if let Some(name) = self.name.value().unwrap_or(Some(NAME.to_string())) {
+            out.write_all(b",\"name\":")?;
+            serde_json::to_writer(&mut *out, &name)?;
+        }
+        if let Some(index) = active_profile_index {
+            out.write_all(b",\"activeProfileIndex\":")?;
+            serde_json::to_writer(&mut *out, &index)?;
+        }
+        if let Some(session_start) = session_start {
+            write!(out, ",\"{}\":", SESSION_START)?;
+            serde_json::to_writer(&mut *out, &session_start)?;
+        }
+        out.write_all(b"}")?;
+        out.flush()
+    }
+}
+
+/// Reads a speedscope, pprof or collapsed-stack file, maybe gzip or zstd compressed.
+/// Files named `.json` or starting with `{` are speedscope files, files named `.pb` or
+/// `.pprof` or with binary content are pprof profiles, anything else is read as folded stacks.
+pub fn read_profile_file(
+    path: &str,
+    import: &ImportOptions,
+) -> Result<speedscope_format::Speedscope> {
+    let file_name = chunk_manifest::file_name(path);
+    let uncompressed_name = Compression::strip_extension(&file_name);
+    let mut reader = file_io::open(path)?;
+    let start = reader.fill_buf().map_err(|e| Error::io(path, e))?;
+    let is_json = start.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'{')
+        || uncompressed_name.ends_with(".json");
+    let is_binary = start
+        .iter()
+        .any(|byte| *byte < b'\t' || (*byte > b'\r' && *byte < b' '));
+    let is_pprof =
+        uncompressed_name.ends_with(".pb") || uncompressed_name.ends_with(".pprof") || is_binary;
+    if is_json {
+        serde_json::from_reader(reader).map_err(|e| Error::parse(path, e))
+    } else if is_pprof {
+        let mut bytes = Vec::new();
+        reader
+            .read_to_end(&mut bytes)
+            .map_err(|e| Error::io(path, e))?;
+        pprof_format::decode_pprof(&bytes)
+            .and_then(|pprof| pprof_format::from_pprof(&pprof, &file_name, &import.pprof))
+            .map_err(|reason| Error::invalid_profile(path, reason))
+    } else {
+        collapsed_format::read_collapsed(reader, &file_name, &import.collapsed)
+            .map_err(|reason| Error::invalid_profile(path, reason))
+    }
+}
+
+/// Reads a profile in any supported format and checks that it can be combined
+pub fn read_valid_speedscope_file(
+    path: &str,
+    import: &ImportOptions,
+) -> Result<speedscope_format::Speedscope> {
+    let speedscope = read_profile_file(path, import)?;
+    speedscope
+        .validate()
+        .map_err(|reason| Error::invalid_profile(path, reason))?;
+    Ok(speedscope)
+}
+
+/// Finds when a chunk started recording, from the manifest or else from the file
+/// modification time minus the chunk's duration, which takes parsing the chunk
+fn start_time(
+    path: &str,
+    manifest_start_times: &HashMap<String, DateTime<Utc>>,
+    import: &ImportOptions,
+) -> Result<DateTime<Utc>> {
+    if let Some(started_at) = manifest_start_times.get(&chunk_manifest::file_name(path)) {
+        return Ok(*started_at);
+    }
+    let modified = std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .map_err(|e| Error::io(path, e))?;
+    let speedscope = read_valid_speedscope_file(path, import)?;
+    let duration = speedscope.duration_seconds().ok_or_else(|| {
+        Error::invalid_profile(
+            path,
+            "profiles without a time unit can't be placed on a timeline",
+        )
+    })?;
+    Ok(DateTime::<Utc>::from(modified) - chrono::Duration::microseconds((duration * 1e6) as i64))
+}
+
+/// Reads, validates and merges the inputs, returning the combiner and the skipped inputs.
+/// `result_path` names the result in the error raised when every input is invalid.
+fn combine_inputs(
+    input_paths: &[String],
+    result_path: &str,
+    config: &CombineConfig,
+) -> Result<(StreamingCombiner, Vec<Error>)> {
+    let mut paths: Vec<&str> = input_paths.iter().map(String::as_str).collect();
+
+    let mut pool = rayon::ThreadPoolBuilder::new();
+    if let Some(jobs) = config.jobs {
+        pool = pool.num_threads(jobs);
+    }
+    let pool = pool
+        .build()
+        .expect("building the combine thread pool should not fail");
+
+    let mut skipped = Vec::new();
+    let mut started_at_by_path: HashMap<&str, DateTime<Utc>> = HashMap::new();
+    if let Some(timeline) = &config.timeline {
+        // every start is needed before the first batch, so chunks that started earlier
+        // but finished later than others still start on the timeline, not before it
+        let manifest_start_times = match &timeline.manifest_path {
+            Some(manifest_path) => chunk_manifest::read_start_times(manifest_path)?,
+            None => HashMap::new(),
+        };
+        let start_times: Vec<Result<DateTime<Utc>>> = pool.install(|| {
+            paths
+                .par_iter()
+                .map(|path| start_time(path, &manifest_start_times, &config.import))
+                .collect()
         });
+        for (path, started_at) in paths.iter().zip(start_times) {
+            match started_at {
+                Ok(started_at) => {
+                    started_at_by_path.insert(path, started_at);
+                }
+                Err(e) if config.skip_invalid => skipped.push(e),
+                Err(e) => return Err(e),
+            }
+        }
+        paths.retain(|path| started_at_by_path.contains_key(path));
+        paths.sort_by_key(|path| started_at_by_path[path]);
+    }
+    let session_start = paths.first().and_then(|path| started_at_by_path.get(path));
+
+    let mut combiner = match (&config.timeline, session_start) {
+        (Some(timeline), Some(session_start)) => {
+            StreamingCombiner::with_timeline(timeline.insert_gaps, *session_start)
+        }
+        // without a session start in timeline mode there are no inputs left to place
+        _ => StreamingCombiner::new(),
     }
-    let new_speedscope = Speedscope {
-        profiles: new_profiles,
-        shared: Shared {
-            frames: new_shared_frames,
+    .with_unit(config.unit)
+    .with_group_by(config.group_by.clone())
+    .with_normalization(config.normalization.clone())
+    .with_skip_invalid(config.skip_invalid);
+    pool.install(|| {
+        for batch in paths.chunks(pool.current_num_threads()) {
+            let parsed: Vec<Result<CombineInput>> = batch
+                .par_iter()
+                .map(|path| {
+                    Ok(CombineInput {
+                        path: path.to_string(),
+                        speedscope: read_valid_speedscope_file(path, &config.import)?,
+                        started_at: started_at_by_path.get(*path).copied(),
+                    })
+                })
+                .collect();
+            let mut inputs = Vec::new();
+            for input in parsed {
+                match input {
+                    Ok(input) => inputs.push(input),
+                    Err(e) if config.skip_invalid => skipped.push(e),
+                    Err(e) => return Err(e),
+                }
+            }
+            skipped.extend(combiner.add_batch(inputs)?);
+        }
+        Ok(())
+    })?;
+
+    if combiner.num_of_inputs() == 0 && !skipped.is_empty() {
+        return Err(Error::invalid_profile(
+            result_path,
+            format!(
+                "nothing to combine, all {} inputs are invalid",
+                skipped.len()
+            ),
+        ));
+    }
+    Ok((combiner, skipped))
+}
+
+/// Combines the inputs like `entry_point`, but returns the combined file instead of writing it
+pub fn combine_in_memory(
+    input_paths: &[String],
+    result_path: &str,
+    config: &CombineConfig,
+) -> Result<(speedscope_format::Speedscope, CombineSummary)> {
+    let (combiner, skipped) = combine_inputs(input_paths, result_path, config)?;
+    let num_of_inputs = combiner.num_of_inputs();
+    let speedscope = combiner
+        .into_speedscope(config.profile_order)
+        .map_err(|e| Error::io(result_path, e))?;
+    Ok((
+        speedscope,
+        CombineSummary {
+            num_of_inputs,
+            skipped,
         },
-        schema: "https://www.speedscope.app/file-format-schema.json".to_string(),
-        exporter: "py-spy@0.4.0".to_string(),
-        name: "py-spy profile".to_string(),
-    };
-    return new_speedscope;
+    ))
 }
 
 /// Combines multiple speedscope files into a single speedscope file
 ///
 /// # Arguments
 ///
-/// * `all_profiles_path` - The path to the file containing the list of all profiles to combine
-/// * `combined_speedscope_path` - The path to the file to write the combined speedscope file to
+/// * `input_paths` - The speedscope files to combine, see `InputSources` for finding them
+/// * `combined_speedscope_path` - The path to write the combined speedscope file to, `-` for stdout.
+///   Compressed with gzip or zstd when it ends with `.gz` or `.zst`
+/// * `config` - How to combine, see `CombineConfig`
 ///
 /// # Example
 ///
 /// ```rust
 /// use combine_speedscope::entry_point;
 ///
-/// entry_point("all_profiles.txt", "combined_speedscope.json");
+/// let inputs = vec!["chunk_1.json".to_string(), "chunk_2.json".to_string()];
+/// entry_point(&inputs, "combined_speedscope.json", &CombineConfig::default());
 /// ```
 ///
-/// This will combine all the profiles listed in the `all_profiles.txt` file into a single speedscope file
+/// This will combine the profiles in `chunk_1.json` and `chunk_2.json` into a single speedscope file
 /// and write it to `combined_speedscope.json`.
+/// Inputs are read `config.jobs` at a time, so hundreds of files can be combined with bounded memory.
+/// Every input is validated before any of it is combined; with `config.skip_invalid` the
+/// invalid ones are listed in the returned summary instead of failing the whole combine.
+/// With `config.timeline` inputs are combined in the order they were recorded and each profile
+/// spans the recorded session. Samples stay back to back, only with `insert_gaps` is the time
+/// between chunks filled so the time axis of the output matches the wall clock.
 pub fn entry_point(
-    all_profiles_path: &str,
+    input_paths: &[String],
     combined_speedscope_path: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let collected_files_str = std::fs::read_to_string(all_profiles_path)?;
-    let collected_files_strings = collected_files_str.lines().collect();
-    let speedscopes = read_speedscope_files(collected_files_strings);
-    let hash_to_new_index_and_frame = create_hash_to_new_index_and_frame(&speedscopes);
-    let adjusted_speedscopes: Vec<speedscope_format::Speedscope> = speedscopes
-        .iter()
-        .map(|speedscope| {
-            adjust_speedscope_to_new_indexes_and_frames(speedscope, &hash_to_new_index_and_frame)
-        })
-        .collect();
-    let combined_speedscope =
-        combine_speedscope_files(adjusted_speedscopes, hash_to_new_index_and_frame);
-    std::fs::write(
-        combined_speedscope_path,
-        serde_json::to_string(&combined_speedscope)?,
-    )?;
-    Ok(())
+    config: &CombineConfig,
+) -> Result<CombineSummary> {
+    file_io::check_overwrite(combined_speedscope_path, config.force)?;
+    let (combiner, skipped) = combine_inputs(input_paths, combined_speedscope_path, config)?;
+    let num_of_inputs = combiner.num_of_inputs();
+    let mut output = file_io::create(combined_speedscope_path, config.force)?;
+    combiner
+        .finish(config.profile_order, &mut output)
+        .and_then(|_| output.finish())
+        .map_err(|e| Error::io(combined_speedscope_path, e))?;
+    Ok(CombineSummary {
+        num_of_inputs,
+        skipped,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use speedscope_format::{Frame, Profile, Shared, Speedscope};
+    use speedscope_format::{
+        EventedProfile, Frame, FrameEvent, FrameEventType, Profile, SampledProfile, Shared,
+        Speedscope,
+    };
+
+    fn frame(name: &str, file: &str, line: u32) -> Frame {
+        Frame {
+            name: name.to_string(),
+            file: Some(file.to_string()),
+            line: Some(line),
+            ..Frame::default()
+        }
+    }
+
+    fn speedscope(profiles: Vec<SampledProfile>, frames: Vec<Frame>) -> Speedscope {
+        Speedscope {
+            profiles: profiles.into_iter().map(Profile::Sampled).collect(),
+            shared: Shared {
+                frames,
+                ..Shared::default()
+            },
+            schema: "test".to_string(),
+            exporter: Some("test".to_string()),
+            name: Some("test".to_string()),
+            active_profile_index: None,
+            extra: Extra::new(),
+        }
+    }
+
+    fn sampled(profile: &Profile) -> &SampledProfile {
+        match profile {
+            Profile::Sampled(profile) => profile,
+            Profile::Evented(_) => panic!("expected a sampled profile"),
+        }
+    }
+
+    /// Adds one parsed input file, `path` is only used for error messages
+    fn add(combiner: &mut StreamingCombiner, path: &str, speedscope: Speedscope) -> Result<()> {
+        combiner
+            .add_batch(vec![CombineInput {
+                path: path.to_string(),
+                speedscope,
+                started_at: None,
+            }])
+            .map(|_| ())
+    }
+
+    fn read_speedscope_file(path: &str) -> Result<Speedscope> {
+        read_profile_file(path, &ImportOptions::default())
+    }
+
+    fn combine(inputs: Vec<Speedscope>) -> Speedscope {
+        let mut combiner = StreamingCombiner::new();
+        for (i, input) in inputs.into_iter().enumerate() {
+            add(&mut combiner, &format!("input_{}.json", i), input).unwrap();
+        }
+        let mut out = Vec::new();
+        combiner.finish(ProfileOrder::FirstSeen, &mut out).unwrap();
+        serde_json::from_slice(&out).unwrap()
+    }
 
     #[test]
-    fn test_read_speedscope_files() {
+    fn test_read_speedscope_file() {
         // Create a temporary file with speedscope content
         let temp_dir = tempfile::tempdir().unwrap();
         let file_path = temp_dir.path().join("test.json");
-        let speedscope = Speedscope {
-            profiles: vec![Profile::default()],
-            shared: Shared {
-                frames: vec![Frame::default()],
-            },
-            schema: "test".to_string(),
-            exporter: "test".to_string(),
-            name: "test".to_string(),
-        };
+        let speedscope = speedscope(vec![SampledProfile::default()], vec![Frame::default()]);
         std::fs::write(&file_path, serde_json::to_string(&speedscope).unwrap()).unwrap();
 
-        let files = vec![file_path.to_str().unwrap()];
-        let result = read_speedscope_files(files);
+        let result = read_speedscope_file(file_path.to_str().unwrap()).unwrap();
 
-        assert_eq!(result.len(), 1);
-        assert_eq!(result[0].schema, "test");
+        assert_eq!(result.schema, "test");
     }
 
     #[test]
-    fn test_create_hash_to_new_index_and_frame() {
-        let frame1 = Frame {
-            name: "func1".to_string(),
-            file: "file1.py".to_string(),
-            line: 1,
-            col: None,
-        };
-        let frame2 = Frame {
-            name: "func2".to_string(),
-            file: "file2.py".to_string(),
-            line: 2,
-            col: None,
-        };
+    fn test_read_speedscope_file_reports_parse_error_with_path() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_path = temp_dir.path().join("truncated.json");
+        std::fs::write(&file_path, "{\"profiles\": [").unwrap();
 
-        let speedscope = Speedscope {
-            profiles: vec![],
-            shared: Shared {
-                frames: vec![frame1.clone(), frame2.clone()],
-            },
-            schema: "".to_string(),
-            exporter: "".to_string(),
-            name: "".to_string(),
-        };
+        let result = read_speedscope_file(file_path.to_str().unwrap());
 
-        let result = create_hash_to_new_index_and_frame(&vec![speedscope]);
+        match result {
+            Err(Error::Parse { path, .. }) => assert!(path.ends_with("truncated.json")),
+            other => panic!("expected parse error, got {:?}", other.map(|s| s.name)),
+        }
+    }
+
+    #[test]
+    fn test_frame_table_interns_each_frame_once() {
+        let mut table = FrameTable::default();
+        let mut intern = |frame: Frame| table.intern(frame.key(), &frame);
 
-        assert_eq!(result.len(), 2);
-        assert!(result.values().any(|(_, f)| f.name == "func1"));
-        assert!(result.values().any(|(_, f)| f.name == "func2"));
+        assert_eq!(intern(frame("func1", "file1.py", 1)), 0);
+        assert_eq!(intern(frame("func2", "file2.py", 2)), 1);
+        assert_eq!(intern(frame("func1", "file1.py", 1)), 0);
+        assert_eq!(table.frames.len(), 2);
     }
 
     #[test]
-    fn test_adjust_speedscope_to_new_indexes_and_frames() {
-        let frame1 = Frame {
-            name: "func1".to_string(),
-            file: "file1.py".to_string(),
-            line: 1,
-            col: None,
-        };
+    fn test_frame_keys_do_not_collide_on_separators() {
+        // each pair rendered to the same "name:file:line:col" string with `Frame::hash`
+        let frames = [
+            frame("<lambda>:x", "app.py", 1),
+            frame("<lambda>", "x:app.py", 1),
+            frame("main", "C:\\app\\main.py", 3),
+            frame("main:C", "\\app\\main.py", 3),
+        ];
+        let mut table = FrameTable::default();
 
-        let original_speedscope = Speedscope {
-            profiles: vec![Profile::default()],
-            shared: Shared {
-                frames: vec![frame1.clone()],
-            },
-            schema: "test".to_string(),
-            exporter: "test".to_string(),
-            name: "test".to_string(),
+        let indexes: Vec<i32> = frames.iter().map(|f| table.intern(f.key(), f)).collect();
+
+        assert_eq!(indexes, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_frames_with_separators_are_interned_across_inputs() {
+        let frames = || {
+            vec![
+                frame("<lambda>:x", "C:\\app\\handlers.py", 12),
+                frame("<lambda>", "x:C:\\app\\handlers.py", 12),
+            ]
+        };
+        let profile = SampledProfile {
+            name: "profile1".to_string(),
+            samples: vec![vec![0, 1]],
+            weights: vec![1.0],
+            ..SampledProfile::default()
         };
 
-        let mut hash_map = std::collections::HashMap::new();
-        hash_map.insert(frame1.hash(), (0, frame1.clone()));
+        let combined = combine(vec![
+            speedscope(vec![profile.clone()], frames()),
+            speedscope(vec![profile], frames()),
+        ]);
 
-        let adjusted = adjust_speedscope_to_new_indexes_and_frames(&original_speedscope, &hash_map);
+        assert_eq!(combined.shared.frames.len(), 2);
+        assert_eq!(combined.shared.frames[0].name, "<lambda>:x");
+        assert_eq!(
+            combined.shared.frames[1].file.as_deref(),
+            Some("x:C:\\app\\handlers.py")
+        );
+        assert_eq!(
+            sampled(&combined.profiles[0]).samples,
+            vec![vec![0, 1], vec![0, 1]]
+        );
+    }
+
+    #[test]
+    fn test_add_remaps_samples_to_shared_frames() {
+        let first = speedscope(
+            vec![SampledProfile {
+                name: "profile1".to_string(),
+                samples: vec![vec![0, 1]],
+                weights: vec![1.0],
+                ..SampledProfile::default()
+            }],
+            vec![frame("func1", "file1.py", 1), frame("func2", "file2.py", 2)],
+        );
+        // same frames in a different order plus a new one
+        let second = speedscope(
+            vec![SampledProfile {
+                name: "profile1".to_string(),
+                samples: vec![vec![1, 0, 2]],
+                weights: vec![2.0],
+                ..SampledProfile::default()
+            }],
+            vec![
+                frame("func2", "file2.py", 2),
+                frame("func1", "file1.py", 1),
+                frame("func3", "file3.py", 3),
+            ],
+        );
+
+        let combined = combine(vec![first, second]);
+
+        assert_eq!(combined.shared.frames.len(), 3);
+        assert_eq!(combined.profiles.len(), 1);
+        assert_eq!(
+            sampled(&combined.profiles[0]).samples,
+            vec![vec![0, 1], vec![0, 1, 2]]
+        );
+    }
+
+    #[test]
+    fn test_add_reports_out_of_range_frame_index() {
+        let broken = speedscope(
+            vec![SampledProfile {
+                name: "profile1".to_string(),
+                samples: vec![vec![0, 5]],
+                weights: vec![1.0],
+                ..SampledProfile::default()
+            }],
+            vec![Frame::default()],
+        );
+
+        let result = add(&mut StreamingCombiner::new(), "broken.json", broken);
 
-        assert_eq!(adjusted.profiles.len(), 1);
-        assert_eq!(adjusted.schema, "test");
-        assert_eq!(adjusted.shared.frames.len(), 1); // Frames are preserved in the adjusted speedscope
+        match result {
+            Err(Error::InvalidProfile { path, reason }) => {
+                assert_eq!(path, "broken.json");
+                assert!(reason.contains("frame 5"));
+            }
+            other => panic!("expected invalid profile error, got {:?}", other),
+        }
     }
 
     #[test]
     fn test_combine_profiles_weights() {
-        let profile1 = Profile {
+        let profile1 = SampledProfile {
             name: "profile1".to_string(),
             unit: "ms".to_string(),
             start_value: 0.0,
             end_value: 100.0,
-            samples: vec![vec![0, 1, 2]],
+            samples: vec![vec![0], vec![0], vec![0]],
             weights: vec![1.0, 2.0, 3.0],
-            r#type: "sampled".to_string(),
+            extra: Extra::new(),
         };
-        let profile2 = Profile {
-            name: "profile2".to_string(),
-            unit: "ms".to_string(),
-            start_value: 0.0,
-            end_value: 100.0,
-            samples: vec![vec![0, 1, 2]],
+        let profile2 = SampledProfile {
             weights: vec![4.0, 5.0, 6.0],
-            r#type: "sampled".to_string(),
+            ..profile1.clone()
         };
 
-        let combined_weights = combine_profiles_weights(&vec![&profile1, &profile2]);
+        let combined = combine(vec![
+            speedscope(vec![profile1], vec![Frame::default()]),
+            speedscope(vec![profile2], vec![Frame::default()]),
+        ]);
 
-        assert_eq!(combined_weights.len(), 6);
-        assert_eq!(combined_weights, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert_eq!(
+            sampled(&combined.profiles[0]).weights,
+            vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]
+        );
+        assert_eq!(sampled(&combined.profiles[0]).end_value, 200.0);
     }
 
     #[test]
-    fn test_extract_same_profile_groups() {
-        let profile1 = Profile {
+    fn test_same_profile_names_are_merged() {
+        let profile1 = SampledProfile {
             name: "profile1".to_string(),
             unit: "ms".to_string(),
+            ..SampledProfile::default()
+        };
+        let profile2 = SampledProfile {
+            name: "profile2".to_string(),
+            ..profile1.clone()
+        };
+
+        let combined = combine(vec![
+            speedscope(vec![profile1.clone()], vec![Frame::default()]),
+            speedscope(vec![profile1, profile2], vec![Frame::default()]),
+        ]);
+
+        let names: Vec<&str> = combined
+            .profiles
+            .iter()
+            .map(|p| sampled(p).name.as_str())
+            .collect();
+        assert_eq!(names, vec!["profile1", "profile2"]);
+    }
+
+    #[test]
+    fn test_interleaved_profiles_read_back_from_shared_spill() {
+        let thread = |name: &str, frame: i32, weight: f64| SampledProfile {
+            name: name.to_string(),
+            samples: vec![vec![frame]],
+            weights: vec![weight],
+            ..SampledProfile::default()
+        };
+        let input = |weight: f64| {
+            speedscope(
+                vec![thread("a", 0, weight), thread("b", 1, weight * 10.0)],
+                vec![frame("func1", "file1.py", 1), frame("func2", "file2.py", 2)],
+            )
+        };
+
+        let combined = combine(vec![input(1.0), input(2.0), input(3.0)]);
+
+        let a = sampled(&combined.profiles[0]);
+        let b = sampled(&combined.profiles[1]);
+        assert_eq!(a.samples, vec![vec![0], vec![0], vec![0]]);
+        assert_eq!(a.weights, vec![1.0, 2.0, 3.0]);
+        assert_eq!(b.samples, vec![vec![1], vec![1], vec![1]]);
+        assert_eq!(b.weights, vec![10.0, 20.0, 30.0]);
+    }
+
+    #[test]
+    fn test_finish_matches_serde_serialization() {
+        let profile = SampledProfile {
+            name: "Process 1 Thread 2".to_string(),
+            unit: "seconds".to_string(),
             start_value: 0.0,
-            end_value: 100.0,
-            samples: vec![],
-            weights: vec![],
-            r#type: "sampled".to_string(),
+            end_value: 0.5,
+            samples: vec![vec![0, 1], vec![1]],
+            weights: vec![0.25, 0.25],
+            extra: Extra::new(),
         };
+        let frames = vec![frame("main", "main.py", 1), frame("work", "main.py", 5)];
+
+        let mut combiner = StreamingCombiner::new();
+        add(
+            &mut combiner,
+            "a.json",
+            speedscope(vec![profile.clone()], frames.clone()),
+        )
+        .unwrap();
+        let mut streamed = Vec::new();
+        combiner
+            .finish(ProfileOrder::FirstSeen, &mut streamed)
+            .unwrap();
 
-        let speedscope1 = Speedscope {
-            profiles: vec![profile1.clone()],
+        let expected = Speedscope {
+            profiles: vec![Profile::Sampled(profile)],
             shared: Shared {
-                frames: vec![Frame::default()],
+                frames,
+                ..Shared::default()
             },
-            schema: "".to_string(),
-            exporter: "".to_string(),
-            name: "".to_string(),
+            schema: SCHEMA.to_string(),
+            exporter: Some("test".to_string()),
+            name: Some("test".to_string()),
+            active_profile_index: None,
+            extra: Extra::new(),
         };
+        assert_eq!(
+            String::from_utf8(streamed).unwrap(),
+            serde_json::to_string(&expected).unwrap()
+        );
+    }
 
-        let speedscope2 = Speedscope {
-            profiles: vec![profile1],
-            shared: Shared {
-                frames: vec![Frame::default()],
+    #[test]
+    fn test_into_speedscope_matches_finish() {
+        let combiner = || {
+            let mut first = named_profiles(&["Thread 2", "Thread 1"]);
+            first.active_profile_index = Some(0);
+            let mut combiner = StreamingCombiner::new();
+            add(&mut combiner, "a.json", first).unwrap();
+            add(&mut combiner, "b.json", named_profiles(&["Thread 1"])).unwrap();
+            combiner
+        };
+        let mut written = Vec::new();
+        combiner().finish(ProfileOrder::Name, &mut written).unwrap();
+
+        let in_memory = combiner().into_speedscope(ProfileOrder::Name).unwrap();
+
+        assert_eq!(in_memory.active_profile_index, Some(1));
+        assert_eq!(serde_json::to_vec(&in_memory).unwrap(), written);
+    }
+
+    #[test]
+    fn test_output_does_not_depend_on_thread_count() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut input_paths = Vec::new();
+        for i in 0..10 {
+            // every input shares some frames with the others and adds its own
+            let frames = (0..5)
+                .map(|j| frame(&format!("func{}", (i + j) % 7), "main.py", j))
+                .collect();
+            let profile = SampledProfile {
+                name: "Process 1 Thread 1".to_string(),
+                unit: "seconds".to_string(),
+                end_value: 1.0,
+                samples: vec![vec![0, 1, 2], vec![0, 3, 4], vec![4]],
+                weights: vec![0.1 * i as f64, 0.2, 0.3],
+                ..SampledProfile::default()
+            };
+            let path = temp_dir.path().join(format!("chunk_{}.json", i));
+            std::fs::write(
+                &path,
+                serde_json::to_string(&speedscope(vec![profile], frames)).unwrap(),
+            )
+            .unwrap();
+            input_paths.push(path.display().to_string());
+        }
+
+        let mut outputs = Vec::new();
+        for jobs in [1, 3, 8] {
+            let output_path = temp_dir.path().join(format!("combined_{}.json", jobs));
+            let summary = entry_point(
+                &input_paths,
+                output_path.to_str().unwrap(),
+                &CombineConfig {
+                    jobs: Some(jobs),
+                    ..CombineConfig::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(summary.num_of_inputs, 10);
+            outputs.push(std::fs::read(output_path).unwrap());
+        }
+
+        assert_eq!(outputs[0], outputs[1]);
+        assert_eq!(outputs[0], outputs[2]);
+    }
+
+    fn named_profiles(names: &[&str]) -> Speedscope {
+        let profiles = names
+            .iter()
+            .map(|name| SampledProfile {
+                name: name.to_string(),
+                unit: "seconds".to_string(),
+                end_value: 1.0,
+                samples: vec![vec![0]],
+                weights: vec![1.0],
+                ..SampledProfile::default()
+            })
+            .collect();
+        speedscope(profiles, vec![frame("main", "main.py", 1)])
+    }
+
+    fn combine_to_bytes(inputs: Vec<Speedscope>, profile_order: ProfileOrder) -> Vec<u8> {
+        let mut combiner = StreamingCombiner::new();
+        for (i, input) in inputs.into_iter().enumerate() {
+            add(&mut combiner, &format!("input_{}.json", i), input).unwrap();
+        }
+        let mut out = Vec::new();
+        combiner.finish(profile_order, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_profiles_keep_first_seen_order() {
+        let combined: Speedscope = serde_json::from_slice(&combine_to_bytes(
+            vec![
+                named_profiles(&["Thread c", "Thread a"]),
+                named_profiles(&["Thread b", "Thread a"]),
+            ],
+            ProfileOrder::FirstSeen,
+        ))
+        .unwrap();
+
+        let names: Vec<&str> = combined
+            .profiles
+            .iter()
+            .map(|p| sampled(p).name.as_str())
+            .collect();
+        assert_eq!(names, vec!["Thread c", "Thread a", "Thread b"]);
+    }
+
+    #[test]
+    fn test_profiles_sorted_by_name() {
+        let combined: Speedscope = serde_json::from_slice(&combine_to_bytes(
+            vec![
+                named_profiles(&["Thread c", "Thread a"]),
+                named_profiles(&["Thread b", "Thread a"]),
+            ],
+            ProfileOrder::Name,
+        ))
+        .unwrap();
+
+        let names: Vec<&str> = combined
+            .profiles
+            .iter()
+            .map(|p| sampled(p).name.as_str())
+            .collect();
+        assert_eq!(names, vec!["Thread a", "Thread b", "Thread c"]);
+    }
+
+    #[test]
+    fn test_combining_same_inputs_twice_gives_identical_bytes() {
+        let inputs = || {
+            vec![
+                named_profiles(&["Thread 5", "Thread 3", "Thread 9", "Thread 1"]),
+                named_profiles(&["Thread 7", "Thread 3", "Thread 2"]),
+                named_profiles(&["Thread 8", "Thread 4", "Thread 6"]),
+            ]
+        };
+
+        for profile_order in [ProfileOrder::FirstSeen, ProfileOrder::Name] {
+            let first = combine_to_bytes(inputs(), profile_order);
+            let second = combine_to_bytes(inputs(), profile_order);
+            assert_eq!(first, second);
+        }
+    }
+
+    fn write_inputs(temp_dir: &std::path::Path, inputs: &[(&str, String)]) -> Vec<String> {
+        inputs
+            .iter()
+            .map(|(name, content)| {
+                let path = temp_dir.join(name);
+                std::fs::write(&path, content).unwrap();
+                path.display().to_string()
+            })
+            .collect()
+    }
+
+    fn valid_input() -> String {
+        serde_json::to_string(&named_profiles(&["Thread 1"])).unwrap()
+    }
+
+    fn invalid_input() -> String {
+        let mut input = named_profiles(&["Thread 1"]);
+        input.profiles[0] = Profile::Sampled(SampledProfile {
+            samples: vec![vec![0, 7]],
+            ..sampled(&input.profiles[0]).clone()
+        });
+        serde_json::to_string(&input).unwrap()
+    }
+
+    #[test]
+    fn test_invalid_input_fails_combine_by_default() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input_paths = write_inputs(
+            temp_dir.path(),
+            &[("good.json", valid_input()), ("bad.json", invalid_input())],
+        );
+        let output_path = temp_dir.path().join("combined.json");
+
+        let result = entry_point(
+            &input_paths,
+            output_path.to_str().unwrap(),
+            &CombineConfig::default(),
+        );
+
+        match result {
+            Err(Error::InvalidProfile { path, .. }) => assert!(path.ends_with("bad.json")),
+            other => panic!("expected invalid profile error, got {:?}", other),
+        }
+        assert!(!output_path.exists());
+    }
+
+    #[test]
+    fn test_skip_invalid_combines_only_good_inputs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input_paths = write_inputs(
+            temp_dir.path(),
+            &[
+                ("good_1.json", valid_input()),
+                ("bad.json", invalid_input()),
+                ("truncated.json", "{\"profiles\": [".to_string()),
+                ("good_2.json", valid_input()),
+            ],
+        );
+        let output_path = temp_dir.path().join("combined.json");
+
+        let summary = entry_point(
+            &input_paths,
+            output_path.to_str().unwrap(),
+            &CombineConfig {
+                skip_invalid: true,
+                ..CombineConfig::default()
             },
-            schema: "".to_string(),
-            exporter: "".to_string(),
-            name: "".to_string(),
+        )
+        .unwrap();
+
+        assert_eq!(summary.num_of_inputs, 2);
+        assert_eq!(summary.skipped.len(), 2);
+        assert!(
+            matches!(&summary.skipped[0], Error::InvalidProfile { path, .. } if path.ends_with("bad.json"))
+        );
+        assert!(
+            matches!(&summary.skipped[1], Error::Parse { path, .. } if path.ends_with("truncated.json"))
+        );
+        let combined = read_speedscope_file(output_path.to_str().unwrap()).unwrap();
+        assert_eq!(sampled(&combined.profiles[0]).weights, vec![1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_validate_rejects_malformed_profiles() {
+        let broken = |change: fn(&mut SampledProfile)| {
+            let mut input = named_profiles(&["Thread 1"]);
+            if let Profile::Sampled(profile) = &mut input.profiles[0] {
+                change(profile);
+            }
+            input.validate()
+        };
+
+        assert!(named_profiles(&["Thread 1"]).validate().is_ok());
+        assert!(broken(|p| p.samples[0] = vec![-1]).is_err());
+        assert!(broken(|p| p.samples[0] = vec![1]).is_err());
+        assert!(broken(|p| p.weights.push(1.0)).is_err());
+        assert!(broken(|p| p.weights[0] = -1.0).is_err());
+        assert!(broken(|p| p.weights[0] = f64::NAN).is_err());
+    }
+
+    fn evented(events: &[(FrameEventType, f64, i32)], end_value: f64) -> Profile {
+        Profile::Evented(EventedProfile {
+            name: "Thread 1".to_string(),
+            unit: "milliseconds".to_string(),
+            start_value: 0.0,
+            end_value,
+            events: events
+                .iter()
+                .map(|(r#type, at, frame)| FrameEvent {
+                    r#type: *r#type,
+                    at: *at,
+                    frame: *frame,
+                    extra: Extra::new(),
+                })
+                .collect(),
+            extra: Extra::new(),
+        })
+    }
+
+    #[test]
+    fn test_evented_profile_parses_from_speedscope_json() {
+        let json = r#"{
+            "type": "evented", "name": "main", "unit": "milliseconds",
+            "startValue": 0, "endValue": 4,
+            "events": [{"type": "O", "at": 0, "frame": 0}, {"type": "C", "at": 4, "frame": 0}]
+        }"#;
+
+        let profile: Profile = serde_json::from_str(json).unwrap();
+
+        match profile {
+            Profile::Evented(profile) => {
+                assert_eq!(profile.events.len(), 2);
+                assert_eq!(profile.events[1].r#type, FrameEventType::Close);
+            }
+            Profile::Sampled(_) => panic!("expected an evented profile"),
+        }
+    }
+
+    #[test]
+    fn test_evented_profile_converts_to_sampled() {
+        use FrameEventType::{Close, Open};
+        // main runs 0..10, calls work 2..5, and nothing runs 10..12
+        let profile = evented(
+            &[
+                (Open, 0.0, 0),
+                (Open, 2.0, 1),
+                (Close, 5.0, 1),
+                (Close, 10.0, 0),
+            ],
+            12.0,
+        );
+
+        let sampled = profile.to_sampled().unwrap();
+
+        assert_eq!(sampled.samples, vec![vec![0], vec![0, 1], vec![0], vec![]]);
+        assert_eq!(sampled.weights, vec![2.0, 3.0, 5.0, 2.0]);
+        assert_eq!(sampled.end_value, 12.0);
+    }
+
+    #[test]
+    fn test_evented_profile_with_mismatched_close_is_invalid() {
+        use FrameEventType::{Close, Open};
+        let input = Speedscope {
+            profiles: vec![evented(
+                &[(Open, 0.0, 0), (Open, 1.0, 1), (Close, 2.0, 0)],
+                2.0,
+            )],
+            ..speedscope(
+                vec![],
+                vec![frame("main", "main.py", 1), frame("work", "main.py", 5)],
+            )
+        };
+
+        let result = input.validate();
+
+        assert!(result.unwrap_err().contains("closes frame 0"));
+    }
+
+    #[test]
+    fn test_evented_profile_with_unclosed_frame_is_invalid() {
+        use FrameEventType::{Close, Open};
+        let input = Speedscope {
+            profiles: vec![evented(
+                &[(Open, 0.0, 0), (Open, 1.0, 1), (Close, 2.0, 1)],
+                3.0,
+            )],
+            ..speedscope(
+                vec![],
+                vec![frame("main", "main.py", 1), frame("work", "main.py", 5)],
+            )
         };
 
-        let speedscopes = vec![speedscope1, speedscope2];
-        let result = extract_same_profile_groups(&speedscopes);
+        let result = input.validate();
+
+        assert!(result.unwrap_err().contains("never closes frame 0"));
+    }
+
+    #[test]
+    fn test_evented_and_sampled_profiles_combine() {
+        use FrameEventType::{Close, Open};
+        let sampled_input = named_profiles(&["Thread 1"]);
+        let evented_input = Speedscope {
+            profiles: vec![evented(&[(Open, 0.0, 0), (Close, 3.0, 0)], 3.0)],
+            ..speedscope(vec![], vec![frame("other", "other.py", 2)])
+        };
+
+        let combined = combine(vec![sampled_input, evented_input]);
+
+        assert_eq!(combined.profiles.len(), 1);
+        let profile = sampled(&combined.profiles[0]);
+        assert_eq!(profile.samples, vec![vec![0], vec![1]]);
+        // The evented profile is in milliseconds, converted to the seconds of the first input
+        assert_eq!(profile.weights, vec![1.0, 0.003]);
+    }
+
+    fn with_unit(mut input: Speedscope, unit: &str) -> Speedscope {
+        for profile in &mut input.profiles {
+            if let Profile::Sampled(profile) = profile {
+                profile.unit = unit.to_string();
+            }
+        }
+        input
+    }
+
+    #[test]
+    fn test_time_units_are_converted_to_first_seen_unit() {
+        let combined = combine(vec![
+            named_profiles(&["Thread 1"]),
+            with_unit(named_profiles(&["Thread 1"]), "milliseconds"),
+        ]);
+
+        let profile = sampled(&combined.profiles[0]);
+        assert_eq!(profile.unit, "seconds");
+        assert_eq!(profile.weights, vec![1.0, 0.001]);
+        assert_eq!(profile.end_value, 1.001);
+    }
+
+    #[test]
+    fn test_output_unit_converts_every_profile() {
+        let mut combiner = StreamingCombiner::new().with_unit(Some(TimeUnit::Milliseconds));
+        add(&mut combiner, "a.json", named_profiles(&["Thread 1"])).unwrap();
+        add(
+            &mut combiner,
+            "b.json",
+            with_unit(named_profiles(&["Thread 1"]), "microseconds"),
+        )
+        .unwrap();
+        let mut out = Vec::new();
+        combiner.finish(ProfileOrder::FirstSeen, &mut out).unwrap();
+        let combined: Speedscope = serde_json::from_slice(&out).unwrap();
+
+        let profile = sampled(&combined.profiles[0]);
+        assert_eq!(profile.unit, "milliseconds");
+        assert_eq!(profile.weights, vec![1000.0, 0.001]);
+    }
+
+    #[test]
+    fn test_sample_counts_do_not_merge_with_durations() {
+        let mut combiner = StreamingCombiner::new();
+        add(&mut combiner, "a.json", named_profiles(&["Thread 1"])).unwrap();
+
+        let result = add(
+            &mut combiner,
+            "b.json",
+            with_unit(named_profiles(&["Thread 1"]), "none"),
+        );
+
+        match result {
+            Err(Error::InvalidProfile { path, reason }) => {
+                assert_eq!(path, "b.json");
+                assert!(reason.contains("\"none\""), "{}", reason);
+            }
+            other => panic!("expected invalid profile error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_skip_invalid_leaves_out_inputs_with_mismatched_units() {
+        let mut counts = with_unit(named_profiles(&["Thread 1"]), "none");
+        counts.shared.frames = vec![frame("collapsed", "collapsed.py", 1)];
+        let input = |path: &str, speedscope: Speedscope| CombineInput {
+            path: path.to_string(),
+            speedscope,
+            started_at: None,
+        };
+        let mut combiner = StreamingCombiner::new().with_skip_invalid(true);
+
+        let skipped = combiner
+            .add_batch(vec![
+                input("a.json", named_profiles(&["Thread 1"])),
+                input("b.txt", counts),
+                input("c.json", named_profiles(&["Thread 1"])),
+            ])
+            .unwrap();
+        let num_of_inputs = combiner.num_of_inputs();
+        let mut out = Vec::new();
+        combiner.finish(ProfileOrder::FirstSeen, &mut out).unwrap();
+        let combined: Speedscope = serde_json::from_slice(&out).unwrap();
+
+        assert_eq!(skipped.len(), 1);
+        assert!(skipped[0].to_string().contains("b.txt"));
+        assert_eq!(num_of_inputs, 2);
+        // nothing of the skipped input was interned
+        assert_eq!(combined.shared.frames.len(), 1);
+        assert_eq!(sampled(&combined.profiles[0]).weights, vec![1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_profiles_without_time_unit_merge_with_same_unit() {
+        let combined = combine(vec![
+            with_unit(named_profiles(&["Thread 1"]), "none"),
+            with_unit(named_profiles(&["Thread 1"]), "none"),
+        ]);
+
+        assert_eq!(sampled(&combined.profiles[0]).unit, "none");
+        assert_eq!(sampled(&combined.profiles[0]).weights, vec![1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_combine_keeps_common_metadata_and_active_profile() {
+        let mut first = named_profiles(&["Thread 1", "Thread 2"]);
+        first.active_profile_index = Some(1);
+        first.exporter = Some("py-spy@0.3.14".to_string());
+        let mut second = named_profiles(&["Thread 3", "Thread 2"]);
+        second.exporter = Some("py-spy@0.3.14".to_string());
+        second.name = Some("other".to_string());
+
+        let combined: Speedscope =
+            serde_json::from_slice(&combine_to_bytes(vec![first, second], ProfileOrder::Name))
+                .unwrap();
+
+        assert_eq!(combined.exporter.as_deref(), Some("py-spy@0.3.14"));
+        assert_eq!(combined.name.as_deref(), Some(NAME));
+        assert_eq!(combined.active_profile_index, Some(1));
+        assert_eq!(sampled(&combined.profiles[1]).name, "Thread 2");
+    }
+
+    fn write_manifest(temp_dir: &std::path::Path, entries: &[(&str, &str)]) -> String {
+        for (name, started_at) in entries {
+            chunk_manifest::append_entry(
+                temp_dir.to_str().unwrap(),
+                &chunk_manifest::ManifestEntry {
+                    path: format!("./profiling_results/{}", name),
+                    started_at: started_at.to_string(),
+                    duration_seconds: 1,
+                },
+            )
+            .unwrap();
+        }
+        temp_dir
+            .join(chunk_manifest::MANIFEST_FILE_NAME)
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn combine_timeline(insert_gaps: bool) -> SampledProfile {
+        let temp_dir = tempfile::tempdir().unwrap();
+        // Listed out of order, the manifest decides where each chunk goes
+        let input_paths = write_inputs(
+            temp_dir.path(),
+            &[("late.json", valid_input()), ("early.json", valid_input())],
+        );
+        let manifest_path = write_manifest(
+            temp_dir.path(),
+            &[
+                ("early.json", "2025-01-01T00:00:10+00:00"),
+                ("late.json", "2025-01-01T00:00:13+00:00"),
+            ],
+        );
+        let output_path = temp_dir.path().join("combined.json");
+
+        entry_point(
+            &input_paths,
+            output_path.to_str().unwrap(),
+            &CombineConfig {
+                timeline: Some(TimelineConfig {
+                    manifest_path: Some(manifest_path),
+                    insert_gaps,
+                }),
+                ..CombineConfig::default()
+            },
+        )
+        .unwrap();
+        let combined = read_speedscope_file(output_path.to_str().unwrap()).unwrap();
+        sampled(&combined.profiles[0]).clone()
+    }
+
+    #[test]
+    fn test_timeline_places_chunks_at_recorded_start() {
+        let profile = combine_timeline(false);
+
+        assert_eq!(profile.start_value, 0.0);
+        assert_eq!(profile.end_value, 4.0);
+        assert_eq!(profile.weights, vec![1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_timeline_fills_gaps_with_empty_samples() {
+        let profile = combine_timeline(true);
+
+        assert_eq!(profile.samples, vec![vec![0], vec![], vec![0]]);
+        assert_eq!(profile.weights, vec![1.0, 2.0, 1.0]);
+        assert_eq!(profile.end_value, 4.0);
+    }
+
+    #[test]
+    fn test_timeline_orders_manifest_and_mtime_chunks_by_start() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input_paths = write_inputs(
+            temp_dir.path(),
+            &[
+                ("listed.json", valid_input()),
+                ("copied.json", valid_input()),
+            ],
+        );
+        // ran 00:00:10 to 00:00:11 by the manifest
+        let manifest_path = write_manifest(
+            temp_dir.path(),
+            &[("listed.json", "2025-01-01T00:00:10+00:00")],
+        );
+        // finished at 00:00:10.5, so it started half a second before the listed chunk
+        let copied_at = DateTime::parse_from_rfc3339("2025-01-01T00:00:10.5+00:00").unwrap();
+        File::options()
+            .write(true)
+            .open(&input_paths[1])
+            .unwrap()
+            .set_modified(copied_at.into())
+            .unwrap();
+        let output_path = temp_dir.path().join("combined.json");
+
+        entry_point(
+            &input_paths,
+            output_path.to_str().unwrap(),
+            &CombineConfig {
+                timeline: Some(TimelineConfig {
+                    manifest_path: Some(manifest_path),
+                    insert_gaps: true,
+                }),
+                ..CombineConfig::default()
+            },
+        )
+        .unwrap();
+
+        let combined = read_speedscope_file(output_path.to_str().unwrap()).unwrap();
+        let profile = sampled(&combined.profiles[0]);
+        assert_eq!(profile.start_value, 0.0);
+        assert_eq!(profile.end_value, 1.5);
+        assert_eq!(profile.weights, vec![1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_timeline_starts_at_earliest_of_overlapping_chunks() {
+        let mut long = named_profiles(&["Thread 1"]);
+        long.profiles[0] = Profile::Sampled(SampledProfile {
+            end_value: 10.0,
+            weights: vec![10.0],
+            ..sampled(&long.profiles[0]).clone()
+        });
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input_paths = write_inputs(
+            temp_dir.path(),
+            &[
+                ("short.json", valid_input()),
+                ("long.json", serde_json::to_string(&long).unwrap()),
+            ],
+        );
+        // ran 00:00:12 to 00:00:13 by the manifest
+        let manifest_path = write_manifest(
+            temp_dir.path(),
+            &[("short.json", "2025-01-01T00:00:12+00:00")],
+        );
+        // finished after the short chunk but started before it, at 00:00:10
+        let copied_at = DateTime::parse_from_rfc3339("2025-01-01T00:00:20+00:00").unwrap();
+        File::options()
+            .write(true)
+            .open(&input_paths[1])
+            .unwrap()
+            .set_modified(copied_at.into())
+            .unwrap();
+        let output_path = temp_dir.path().join("combined.json");
+
+        entry_point(
+            &input_paths,
+            output_path.to_str().unwrap(),
+            &CombineConfig {
+                timeline: Some(TimelineConfig {
+                    manifest_path: Some(manifest_path),
+                    insert_gaps: false,
+                }),
+                ..CombineConfig::default()
+            },
+        )
+        .unwrap();
+
+        let combined = read_speedscope_file(output_path.to_str().unwrap()).unwrap();
+        let profile = sampled(&combined.profiles[0]);
+        assert_eq!(profile.start_value, 0.0);
+        assert_eq!(profile.end_value, 10.0);
+        assert_eq!(profile.weights, vec![10.0, 1.0]);
+        let session_start = DateTime::parse_from_rfc3339("2025-01-01T00:00:10+00:00").unwrap();
+        assert_eq!(combined.session_start(), Some(session_start.into()));
+    }
+
+    #[test]
+    fn test_timeline_rejects_profiles_without_time_unit() {
+        let mut input = named_profiles(&["Thread 1"]);
+        input.profiles[0] = Profile::Sampled(SampledProfile {
+            unit: "none".to_string(),
+            ..sampled(&input.profiles[0]).clone()
+        });
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input_paths = write_inputs(
+            temp_dir.path(),
+            &[("counts.json", serde_json::to_string(&input).unwrap())],
+        );
+
+        let result = entry_point(
+            &input_paths,
+            temp_dir.path().join("combined.json").to_str().unwrap(),
+            &CombineConfig {
+                timeline: Some(TimelineConfig::default()),
+                ..CombineConfig::default()
+            },
+        );
+
+        assert!(matches!(result, Err(Error::InvalidProfile { .. })));
+    }
+
+    #[test]
+    fn test_thread_name_grouping_merges_across_restarts() {
+        let mut combiner = StreamingCombiner::new().with_group_by(GroupBy::ThreadName);
+        add(
+            &mut combiner,
+            "before.json",
+            named_profiles(&[r#"Process 1 Thread 0x7F4E1A2B3740 "MainThread""#]),
+        )
+        .unwrap();
+        add(
+            &mut combiner,
+            "after.json",
+            named_profiles(&[r#"Process 7 Thread 0x7F9C55D01740 "MainThread""#]),
+        )
+        .unwrap();
+        let mut out = Vec::new();
+        combiner.finish(ProfileOrder::FirstSeen, &mut out).unwrap();
+        let combined: Speedscope = serde_json::from_slice(&out).unwrap();
+
+        assert_eq!(combined.profiles.len(), 1);
+        assert_eq!(sampled(&combined.profiles[0]).name, "MainThread");
+        assert_eq!(sampled(&combined.profiles[0]).weights, vec![1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_normalized_frames_merge_across_builds() {
+        let mut combiner = StreamingCombiner::new().with_normalization(FrameNormalization {
+            strip_lines: true,
+            module_names: true,
+            ..FrameNormalization::default()
+        });
+        for (path, file, line) in [
+            (
+                "old_build.json",
+                "/usr/local/lib/python3.10/site-packages/django/views.py",
+                10,
+            ),
+            (
+                "new_build.json",
+                "/opt/venv/lib/python3.11/site-packages/django/views.py",
+                14,
+            ),
+        ] {
+            let input = speedscope(
+                vec![sampled(&named_profiles(&["Thread 1"]).profiles[0]).clone()],
+                vec![frame("dispatch", file, line)],
+            );
+            add(&mut combiner, path, input).unwrap();
+        }
+        let mut out = Vec::new();
+        combiner.finish(ProfileOrder::FirstSeen, &mut out).unwrap();
+        let combined: Speedscope = serde_json::from_slice(&out).unwrap();
+
+        assert_eq!(combined.shared.frames.len(), 1);
+        assert_eq!(
+            combined.shared.frames[0].file.as_deref(),
+            Some("django.views")
+        );
+        assert_eq!(combined.shared.frames[0].line, None);
+        assert_eq!(
+            sampled(&combined.profiles[0]).samples,
+            vec![vec![0], vec![0]]
+        );
+    }
+
+    #[test]
+    fn test_collapsed_inputs_combine_with_speedscope_inputs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input_paths = write_inputs(
+            temp_dir.path(),
+            &[
+                ("chunk.json", valid_input()),
+                ("old_capture.txt", "main (main.py:1) 50\n".to_string()),
+            ],
+        );
+        let output_path = temp_dir.path().join("combined.json");
+
+        entry_point(
+            &input_paths,
+            output_path.to_str().unwrap(),
+            &CombineConfig {
+                group_by: GroupBy::All,
+                import: ImportOptions {
+                    collapsed: CollapsedImportOptions {
+                        sample_rate: Some(100.0),
+                        ..CollapsedImportOptions::default()
+                    },
+                    ..ImportOptions::default()
+                },
+                ..CombineConfig::default()
+            },
+        )
+        .unwrap();
+
+        let combined = read_speedscope_file(output_path.to_str().unwrap()).unwrap();
+        assert_eq!(combined.shared.frames.len(), 1);
+        assert_eq!(
+            sampled(&combined.profiles[0]).samples,
+            vec![vec![0], vec![0]]
+        );
+        assert_eq!(sampled(&combined.profiles[0]).weights, vec![1.0, 0.5]);
+    }
+
+    #[test]
+    fn test_pprof_inputs_combine_with_speedscope_inputs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input_paths = write_inputs(temp_dir.path(), &[("chunk.json", valid_input())]);
+        let pprof_path = temp_dir.path().join("cpu.pb.gz");
+        let pprof = pprof_format::encode_pprof(&named_profiles(&["Thread 1"])).unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(
+            File::create(&pprof_path).unwrap(),
+            flate2::Compression::default(),
+        );
+        encoder.write_all(&pprof).unwrap();
+        encoder.finish().unwrap();
+        let output_path = temp_dir.path().join("combined.json");
+
+        entry_point(
+            &[input_paths[0].clone(), pprof_path.display().to_string()],
+            output_path.to_str().unwrap(),
+            &CombineConfig::default(),
+        )
+        .unwrap();
 
-        assert_eq!(result.len(), 1);
-        assert_eq!(result.keys().next().unwrap(), "profile1");
+        let combined = read_speedscope_file(output_path.to_str().unwrap()).unwrap();
+        assert_eq!(combined.shared.frames.len(), 1);
+        let profile = sampled(&combined.profiles[0]);
+        assert_eq!(profile.name, "Thread 1");
+        assert_eq!(profile.unit, "seconds");
+        assert_eq!(profile.samples, vec![vec![0], vec![0]]);
+        assert_eq!(profile.weights, vec![1.0, 1.0]);
     }
 }
@@ -0,0 +1,583 @@
+use crate::collapsed_format::frame_label;
+use crate::combine_speedscope::{combine_in_memory, CombineConfig, FrameTable, ImportOptions};
+use crate::error::{Error, Result};
+use crate::file_io;
+use crate::flamegraph::{self, FlameGraph};
+use crate::frame_normalization::FrameNormalization;
+use crate::profile_grouping::GroupBy;
+use crate::speedscope_format::{
+    seconds_per_unit, Extra, Frame, Profile, SampledProfile, Shared, Speedscope,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write as _;
+
+/// One side of the comparison, a single profile or the chunks of a session
+#[derive(Debug, Clone)]
+pub struct DiffInput {
+    /// Shown in the report and in errors, such as the path the user gave
+    pub name: String,
+    pub paths: Vec<String>,
+}
+
+/// Options for comparing two profiles
+#[derive(Debug, Default)]
+pub struct DiffConfig {
+    /// Number of threads used to parse and remap inputs, defaults to the number of cores
+    pub jobs: Option<usize>,
+    /// Leave out inputs that can't be read or fail validation instead of failing
+    pub skip_invalid: bool,
+    /// Rules applied to frames of both sides before they are interned
+    pub normalization: FrameNormalization,
+    pub import: ImportOptions,
+    /// Title of the differential flamegraph
+    pub title: Option<String>,
+    /// Replace the output file if it already exists
+    pub force: bool,
+}
+
+/// Change of one function, inclusive values count the function once per sample
+/// even when it recurses
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionDelta {
+    pub name: String,
+    pub file: Option<String>,
+    pub base_self: f64,
+    pub new_self: f64,
+    pub base_total: f64,
+    pub new_total: f64,
+}
+
+impl FunctionDelta {
+    pub fn delta(&self) -> f64 {
+        self.new_total - self.base_total
+    }
+
+    pub fn self_delta(&self) -> f64 {
+        self.new_self - self.base_self
+    }
+}
+
+/// Change of one distinct stack, root first
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackDelta {
+    pub frames: Vec<String>,
+    pub base: f64,
+    pub new: f64,
+}
+
+impl StackDelta {
+    pub fn delta(&self) -> f64 {
+        self.new - self.base
+    }
+}
+
+/// Result of comparing two profiles, new values are scaled so both sides have the base total
+#[derive(Debug)]
+pub struct DiffReport {
+    pub base_name: String,
+    pub new_name: String,
+    pub base_inputs: usize,
+    pub new_inputs: usize,
+    /// Inputs left out because of `DiffConfig::skip_invalid`
+    pub skipped: Vec<Error>,
+    /// Unit of all values, `seconds` when both sides are measured in time
+    pub unit: String,
+    pub base_total: f64,
+    /// Total of the new side before it was scaled to `base_total`
+    pub new_total: f64,
+    /// Functions that grew, largest growth first
+    pub regressions: Vec<FunctionDelta>,
+    /// Functions that shrank, largest drop first
+    pub improvements: Vec<FunctionDelta>,
+    /// Stacks that changed, largest change either way first
+    pub stacks: Vec<StackDelta>,
+}
+
+/// One side combined into a single profile over the shared frame table
+struct Side {
+    profile: SampledProfile,
+    inputs: usize,
+    skipped: Vec<Error>,
+    total: f64,
+}
+
+/// Combines one side into a single profile, converting time units to seconds,
+/// and interns its frames into `frames`
+fn combine_side(input: &DiffInput, config: &DiffConfig, frames: &mut FrameTable) -> Result<Side> {
+    let combine_config = CombineConfig {
+        jobs: config.jobs,
+        skip_invalid: config.skip_invalid,
+        group_by: GroupBy::All,
+        normalization: config.normalization.clone(),
+        import: config.import.clone(),
+        ..CombineConfig::default()
+    };
+    let (combined, summary) = combine_in_memory(&input.paths, &input.name, &combine_config)?;
+    let new_indexes: Vec<i32> = combined
+        .shared
+        .frames
+        .iter()
+        .map(|frame| frames.intern(frame.key(), frame))
+        .collect();
+    let mut profile = match combined.profiles.first() {
+        Some(profile) => profile
+            .to_sampled()
+            .map_err(|reason| Error::invalid_profile(&input.name, reason))?
+            .into_owned(),
+        None => SampledProfile {
+            unit: "seconds".to_string(),
+            ..SampledProfile::default()
+        },
+    };
+    let scale = seconds_per_unit(&profile.unit);
+    if let Some(scale) = scale {
+        profile.unit = "seconds".to_string();
+        profile
+            .weights
+            .iter_mut()
+            .for_each(|weight| *weight *= scale);
+    }
+    for sample in &mut profile.samples {
+        for index in sample.iter_mut() {
+            *index = new_indexes[*index as usize];
+        }
+    }
+    let total = profile
+        .samples
+        .iter()
+        .zip(&profile.weights)
+        .filter(|(sample, _)| !sample.is_empty())
+        .map(|(_, weight)| weight)
+        .sum();
+    Ok(Side {
+        profile,
+        inputs: summary.num_of_inputs,
+        skipped: summary.skipped,
+        total,
+    })
+}
+
+/// Summed weight of every distinct non-empty stack, ordered so sums don't depend on hashing
+fn stack_values(profile: &SampledProfile) -> BTreeMap<&[i32], f64> {
+    let mut stacks: BTreeMap<&[i32], f64> = BTreeMap::new();
+    for (sample, weight) in profile.samples.iter().zip(&profile.weights) {
+        if !sample.is_empty() {
+            *stacks.entry(sample.as_slice()).or_default() += weight;
+        }
+    }
+    stacks
+}
+
+type FunctionKey<'a> = (&'a str, Option<&'a str>);
+
+/// Adds the self and inclusive values of every function of `stacks` to `functions`,
+/// `new` picks the side they are added to
+fn add_function_values<'a>(
+    stacks: &BTreeMap<&[i32], f64>,
+    frames: &'a [Frame],
+    new: bool,
+    functions: &mut HashMap<FunctionKey<'a>, FunctionDelta>,
+) {
+    for (stack, value) in stacks {
+        let mut seen: HashSet<FunctionKey> = HashSet::new();
+        for (depth, index) in stack.iter().enumerate() {
+            let frame = &frames[*index as usize];
+            let key = (frame.name.as_str(), frame.file.as_deref());
+            let function = functions.entry(key).or_insert_with(|| FunctionDelta {
+                name: frame.name.clone(),
+                file: frame.file.clone(),
+                base_self: 0.0,
+                new_self: 0.0,
+                base_total: 0.0,
+                new_total: 0.0,
+            });
+            let (self_value, total_value) = if new {
+                (&mut function.new_self, &mut function.new_total)
+            } else {
+                (&mut function.base_self, &mut function.base_total)
+            };
+            if depth == stack.len() - 1 {
+                *self_value += value;
+            }
+            if seen.insert(key) {
+                *total_value += value;
+            }
+        }
+    }
+}
+
+fn speedscope_of(profile: SampledProfile, frames: &[Frame]) -> Speedscope {
+    Speedscope {
+        profiles: vec![Profile::Sampled(profile)],
+        shared: Shared {
+            frames: frames.to_vec(),
+            extra: Extra::new(),
+        },
+        schema: String::new(),
+        exporter: None,
+        name: None,
+        active_profile_index: None,
+        extra: Extra::new(),
+    }
+}
+
+/// Compares two profiles and writes a differential flamegraph to `output_path`, `-` for stdout
+///
+/// Each side is combined into one profile the way `combine-speedscope-files --group-by all`
+/// would, then frames of both sides are interned into one table so equal frames match.
+/// The new side is scaled to the total of the base side, so deltas show how the share of
+/// each stack and function changed rather than how long each recording was.
+/// The flamegraph has the shape of the new profile, red frames grew and blue frames shrank.
+pub fn entry_point(
+    base: &DiffInput,
+    new: &DiffInput,
+    output_path: &str,
+    config: &DiffConfig,
+) -> Result<DiffReport> {
+    file_io::check_overwrite(output_path, config.force)?;
+    let mut frame_table = FrameTable::default();
+    let base_side = combine_side(base, config, &mut frame_table)?;
+    let mut new_side = combine_side(new, config, &mut frame_table)?;
+    let frames = frame_table.into_frames();
+
+    if base_side.profile.unit != new_side.profile.unit {
+        return Err(Error::invalid_profile(
+            &new.name,
+            format!(
+                "profiles in {:?} can't be compared with base profiles in {:?}",
+                new_side.profile.unit, base_side.profile.unit
+            ),
+        ));
+    }
+    if new_side.total > 0.0 {
+        let factor = base_side.total / new_side.total;
+        new_side
+            .profile
+            .weights
+            .iter_mut()
+            .for_each(|weight| *weight *= factor);
+    }
+
+    let base_stacks = stack_values(&base_side.profile);
+    let new_stacks = stack_values(&new_side.profile);
+    let mut functions = HashMap::new();
+    add_function_values(&base_stacks, &frames, false, &mut functions);
+    add_function_values(&new_stacks, &frames, true, &mut functions);
+    let label = |stack: &[i32]| -> Vec<String> {
+        stack
+            .iter()
+            .map(|index| frame_label(&frames[*index as usize], true))
+            .collect()
+    };
+    let mut stacks: Vec<StackDelta> = base_stacks
+        .keys()
+        .chain(
+            new_stacks
+                .keys()
+                .filter(|stack| !base_stacks.contains_key(*stack)),
+        )
+        .map(|stack| StackDelta {
+            frames: label(stack),
+            base: base_stacks.get(stack).copied().unwrap_or_default(),
+            new: new_stacks.get(stack).copied().unwrap_or_default(),
+        })
+        .filter(|stack| stack.delta() != 0.0)
+        .collect();
+    stacks.sort_by(|a, b| {
+        b.delta()
+            .abs()
+            .total_cmp(&a.delta().abs())
+            .then_with(|| a.frames.cmp(&b.frames))
+    });
+    let by_name = |a: &FunctionDelta, b: &FunctionDelta| {
+        a.name.cmp(&b.name).then_with(|| a.file.cmp(&b.file))
+    };
+    let mut regressions: Vec<FunctionDelta> = functions
+        .values()
+        .filter(|function| function.delta() > 0.0)
+        .cloned()
+        .collect();
+    regressions.sort_by(|a, b| b.delta().total_cmp(&a.delta()).then_with(|| by_name(a, b)));
+    let mut improvements: Vec<FunctionDelta> = functions
+        .values()
+        .filter(|function| function.delta() < 0.0)
+        .cloned()
+        .collect();
+    improvements.sort_by(|a, b| a.delta().total_cmp(&b.delta()).then_with(|| by_name(a, b)));
+
+    let unit = base_side.profile.unit.clone();
+    let base_graph = FlameGraph::from_speedscope(&speedscope_of(base_side.profile, &frames), false)
+        .map_err(|reason| Error::invalid_profile(&base.name, reason))?;
+    let mut new_graph =
+        FlameGraph::from_speedscope(&speedscope_of(new_side.profile, &frames), false)
+            .map_err(|reason| Error::invalid_profile(&new.name, reason))?;
+    new_graph.root.set_baseline(Some(&base_graph.root));
+    let title = config
+        .title
+        .clone()
+        .unwrap_or_else(|| format!("{} vs {}", new.name, base.name));
+    let subtitle = format!(
+        "{} scaled to the {} total of {}; red grew, blue shrank",
+        new.name,
+        flamegraph::format_value(base_side.total, &unit),
+        base.name
+    );
+    let mut output = file_io::create(output_path, config.force)?;
+    flamegraph::write_differential_flamegraph(
+        &new_graph,
+        base_side.total,
+        &title,
+        &subtitle,
+        &mut output,
+    )
+    .and_then(|_| output.finish())
+    .map_err(|e| Error::io(output_path, e))?;
+
+    let mut skipped = base_side.skipped;
+    skipped.extend(new_side.skipped);
+    Ok(DiffReport {
+        base_name: base.name.clone(),
+        new_name: new.name.clone(),
+        base_inputs: base_side.inputs,
+        new_inputs: new_side.inputs,
+        skipped,
+        unit,
+        base_total: base_side.total,
+        new_total: new_side.total,
+        regressions,
+        improvements,
+        stacks,
+    })
+}
+
+fn function_row(function: &FunctionDelta, total: f64, out: &mut String) {
+    let percent = |value: f64| value / total * 100.0;
+    let location = function
+        .file
+        .as_ref()
+        .map(|file| format!(" ({})", file))
+        .unwrap_or_default();
+    let _ = writeln!(
+        out,
+        "{:>8.2}% {:>8.2}% {:>+8.2}% {:>+10.2}%  {}{}",
+        percent(function.base_total),
+        percent(function.new_total),
+        percent(function.delta()),
+        percent(function.self_delta()),
+        function.name,
+        location
+    );
+}
+
+/// The `top` largest regressions, improvements and stack changes as a text table.
+/// Values are percent of the total, which both sides share after scaling.
+pub fn format_table(report: &DiffReport, top: usize) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "Base: {} ({} inputs, {})",
+        report.base_name,
+        report.base_inputs,
+        flamegraph::format_value(report.base_total, &report.unit)
+    );
+    let _ = writeln!(
+        out,
+        "New:  {} ({} inputs, {}, scaled to the base total)",
+        report.new_name,
+        report.new_inputs,
+        flamegraph::format_value(report.new_total, &report.unit)
+    );
+    if report.base_total <= 0.0 {
+        let _ = writeln!(out, "\nThe base profile is empty, nothing to compare");
+        return out;
+    }
+    let header = format!(
+        "{:>9} {:>9} {:>9} {:>11}  function",
+        "base", "new", "delta", "self delta"
+    );
+    for (title, functions) in [
+        ("Regressions", &report.regressions),
+        ("Improvements", &report.improvements),
+    ] {
+        let _ = writeln!(out, "\n{} ({} functions)", title, functions.len());
+        if functions.is_empty() {
+            continue;
+        }
+        let _ = writeln!(out, "{}", header);
+        for function in functions.iter().take(top) {
+            function_row(function, report.base_total, &mut out);
+        }
+    }
+    let _ = writeln!(out, "\nStacks with the largest changes");
+    for stack in report.stacks.iter().take(top) {
+        let _ = writeln!(
+            out,
+            "{:>+8.2}%  {}",
+            stack.delta() / report.base_total * 100.0,
+            stack.frames.join(";")
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(name: &str, line: u32) -> Frame {
+        Frame {
+            name: name.to_string(),
+            file: Some("app.py".to_string()),
+            line: Some(line),
+            ..Frame::default()
+        }
+    }
+
+    /// Writes a profile with the given stacks of frame names and weights in milliseconds
+    fn write_profile(dir: &std::path::Path, file: &str, stacks: &[(&[&str], f64)]) -> DiffInput {
+        let mut frames: Vec<Frame> = Vec::new();
+        let mut samples = Vec::new();
+        let mut weights = Vec::new();
+        for (stack, weight) in stacks {
+            let sample = stack
+                .iter()
+                .map(|name| match frames.iter().position(|f| f.name == *name) {
+                    Some(index) => index as i32,
+                    None => {
+                        frames.push(frame(name, 1));
+                        frames.len() as i32 - 1
+                    }
+                })
+                .collect();
+            samples.push(sample);
+            weights.push(*weight);
+        }
+        let speedscope = Speedscope {
+            schema: crate::combine_speedscope::SCHEMA.to_string(),
+            ..speedscope_of(
+                SampledProfile {
+                    name: "MainThread".to_string(),
+                    unit: "milliseconds".to_string(),
+                    end_value: weights.iter().sum(),
+                    samples,
+                    weights,
+                    ..SampledProfile::default()
+                },
+                &frames,
+            )
+        };
+        let path = dir.join(file);
+        std::fs::write(&path, serde_json::to_string(&speedscope).unwrap()).unwrap();
+        DiffInput {
+            name: file.to_string(),
+            paths: vec![path.display().to_string()],
+        }
+    }
+
+    fn diff(base: &[(&[&str], f64)], new: &[(&[&str], f64)]) -> (DiffReport, String) {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let base = write_profile(temp_dir.path(), "base.json", base);
+        let new = write_profile(temp_dir.path(), "new.json", new);
+        let output = temp_dir.path().join("diff.svg");
+        let report = entry_point(
+            &base,
+            &new,
+            output.to_str().unwrap(),
+            &DiffConfig::default(),
+        )
+        .unwrap();
+        (report, std::fs::read_to_string(output).unwrap())
+    }
+
+    #[test]
+    fn test_new_side_is_scaled_to_base_total() {
+        let (report, _) = diff(
+            &[(&["main", "query"], 50.0), (&["main", "render"], 50.0)],
+            // twice as long a recording, but query takes a larger share
+            &[(&["main", "query"], 150.0), (&["main", "render"], 50.0)],
+        );
+
+        assert!((report.base_total - 0.1).abs() < 1e-12);
+        assert!((report.new_total - 0.2).abs() < 1e-12);
+        assert_eq!(report.unit, "seconds");
+        let regressions: Vec<(&str, f64)> = report
+            .regressions
+            .iter()
+            .map(|f| (f.name.as_str(), (f.delta() * 1000.0).round()))
+            .collect();
+        assert_eq!(regressions, vec![("query", 25.0)]);
+        assert_eq!(report.improvements[0].name, "render");
+        assert!((report.improvements[0].self_delta() + 0.025).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_functions_count_once_per_stack_when_recursing() {
+        let (report, _) = diff(&[(&["main"], 10.0)], &[(&["main", "walk", "walk"], 10.0)]);
+
+        let walk = &report.regressions[0];
+        assert_eq!(walk.name, "walk");
+        assert!((walk.new_total - 0.01).abs() < 1e-12);
+        assert!((walk.new_self - 0.01).abs() < 1e-12);
+        assert!(report.improvements.is_empty());
+    }
+
+    #[test]
+    fn test_stacks_are_ranked_by_change() {
+        let (report, _) = diff(
+            &[(&["main", "a"], 10.0), (&["main", "b"], 30.0)],
+            &[(&["main", "a"], 30.0), (&["main", "c"], 10.0)],
+        );
+
+        let stacks: Vec<(String, f64)> = report
+            .stacks
+            .iter()
+            .map(|s| (s.frames.join(";"), (s.delta() * 1000.0).round()))
+            .collect();
+        assert_eq!(
+            stacks,
+            vec![
+                ("main (app.py:1);b (app.py:1)".to_string(), -30.0),
+                ("main (app.py:1);a (app.py:1)".to_string(), 20.0),
+                ("main (app.py:1);c (app.py:1)".to_string(), 10.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_differential_flamegraph_and_table() {
+        let (report, svg) = diff(
+            &[(&["main", "query"], 50.0), (&["main", "render"], 50.0)],
+            &[(&["main", "query"], 75.0), (&["main", "render"], 25.0)],
+        );
+
+        assert!(svg.contains("query (app.py:1): 50.00% -&gt; 75.00% (+25.00%)"));
+        let table = format_table(&report, 10);
+        assert!(table.contains("Regressions (1 functions)"));
+        assert!(table.contains("   50.00%    75.00%   +25.00%     +25.00%  query (app.py)"));
+        assert!(table.contains("  -25.00%  main (app.py:1);render (app.py:1)"));
+    }
+
+    #[test]
+    fn test_sample_counts_do_not_compare_with_durations() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let base = write_profile(temp_dir.path(), "base.json", &[(&["main"], 10.0)]);
+        let new_path = temp_dir.path().join("new.txt");
+        std::fs::write(&new_path, "main (app.py:1) 3\n").unwrap();
+        let new = DiffInput {
+            name: "new.txt".to_string(),
+            paths: vec![new_path.display().to_string()],
+        };
+
+        let result = entry_point(
+            &base,
+            &new,
+            temp_dir.path().join("diff.svg").to_str().unwrap(),
+            &DiffConfig::default(),
+        );
+
+        match result {
+            Err(Error::InvalidProfile { path, .. }) => assert_eq!(path, "new.txt"),
+            other => panic!("expected invalid profile error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt;
+
+/// Errors that can end a run of any of the commands.
+/// Every variant carries the path, pod or chunk it is about so the message is actionable.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a local file failed
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    /// A file is not valid speedscope JSON
+    Parse {
+        path: String,
+        source: serde_json::Error,
+    },
+    /// A kubectl invocation could not be run or exited unsuccessfully
+    Kubectl {
+        action: String,
+        target: String,
+        reason: String,
+    },
+    /// py-spy is missing from the pod and installing it failed
+    Install { pod_name: String, reason: String },
+    /// A speedscope file parsed but its content is not usable
+    InvalidProfile { path: String, reason: String },
+    /// The chunk manifest written by the collector can't be read back
+    InvalidManifest { path: String, reason: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn io(path: impl Into<String>, source: std::io::Error) -> Self {
+        Error::Io {
+            path: path.into(),
+            source,
+        }
+    }
+
+    pub fn parse(path: impl Into<String>, source: serde_json::Error) -> Self {
+        Error::Parse {
+            path: path.into(),
+            source,
+        }
+    }
+
+    pub fn invalid_profile(path: impl Into<String>, reason: impl Into<String>) -> Self {
+        Error::InvalidProfile {
+            path: path.into(),
+            reason: reason.into(),
+        }
+    }
+
+    pub fn invalid_manifest(path: impl Into<String>, reason: impl Into<String>) -> Self {
+        Error::InvalidManifest {
+            path: path.into(),
+            reason: reason.into(),
+        }
+    }
+
+    /// Process exit code for this error, distinct per variant
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Io { .. } => 5,
+            Error::Parse { .. } => 6,
+            Error::Kubectl { .. } => 4,
+            Error::Install { .. } => 3,
+            Error::InvalidProfile { .. } => 7,
+            Error::InvalidManifest { .. } => 8,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "I/O error on {}: {}", path, source),
+            Error::Parse { path, source } => {
+                write!(f, "failed to parse speedscope file {}: {}", path, source)
+            }
+            Error::Kubectl {
+                action,
+                target,
+                reason,
+            } => write!(f, "kubectl {} failed for {}: {}", action, target, reason),
+            Error::Install { pod_name, reason } => {
+                write!(
+                    f,
+                    "failed to install py-spy in pod {}: {}",
+                    pod_name, reason
+                )
+            }
+            Error::InvalidProfile { path, reason } => {
+                write!(f, "invalid speedscope file {}: {}", path, reason)
+            }
+            Error::InvalidManifest { path, reason } => {
+                write!(f, "invalid chunk manifest {}: {}", path, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Parse { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes_differ_by_variant() {
+        let errors = [
+            Error::io("a.json", std::io::Error::other("boom")),
+            Error::parse("a.json", serde_json::from_str::<u32>("x").unwrap_err()),
+            Error::Kubectl {
+                action: "cp".to_string(),
+                target: "default/pod:/tmp/a.json".to_string(),
+                reason: "exited with 1".to_string(),
+            },
+            Error::Install {
+                pod_name: "pod".to_string(),
+                reason: "pip not found".to_string(),
+            },
+            Error::invalid_profile("a.json", "frame 3 out of range"),
+            Error::invalid_manifest("manifest.jsonl", "line 1: expected value"),
+        ];
+
+        let mut codes: Vec<u8> = errors.iter().map(|e| e.exit_code()).collect();
+        codes.sort();
+        codes.dedup();
+
+        assert_eq!(codes.len(), errors.len());
+        assert!(!codes.contains(&0));
+        assert!(!codes.contains(&2), "2 is reserved for usage errors");
+    }
+
+    #[test]
+    fn test_message_names_offending_path() {
+        let error = Error::invalid_profile("chunks/pyspy_output_1.json", "frame 3 out of range");
+
+        assert_eq!(
+            error.to_string(),
+            "invalid speedscope file chunks/pyspy_output_1.json: frame 3 out of range"
+        );
+    }
+}
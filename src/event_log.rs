@@ -0,0 +1,310 @@
+use chrono::Utc;
+use colored::*;
+use serde::Serialize;
+use std::cell::Cell;
+use std::process::Stdio;
+
+/// How progress is reported to the user
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Colored, human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line on stdout, meant for automation
+    Json,
+}
+
+/// Machine readable events, serialized as `{"timestamp": ..., "event": "<name>", ...fields}`
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    SessionStarted {
+        pod_name: String,
+        namespace: String,
+        duration_seconds: u16,
+        num_of_samples: u16,
+        output_dir: String,
+    },
+    ChunkStarted {
+        chunk: u16,
+        remote_file: String,
+    },
+    ChunkCopied {
+        chunk: u16,
+        local_path: String,
+    },
+    ChunkFailed {
+        chunk: u16,
+        error: String,
+    },
+    InstallResult {
+        already_installed: bool,
+        success: bool,
+        error: Option<String>,
+    },
+    InputSkipped {
+        error: String,
+    },
+    CombineFinished {
+        inputs: usize,
+        skipped: usize,
+        output: String,
+        success: bool,
+        error: Option<String>,
+    },
+    ExportFinished {
+        input: String,
+        output: String,
+        format: String,
+        success: bool,
+        error: Option<String>,
+    },
+    ReportFinished {
+        inputs: usize,
+        output: String,
+        success: bool,
+        error: Option<String>,
+    },
+    DiffFinished {
+        base: String,
+        new: String,
+        output: String,
+        regressions: usize,
+        improvements: usize,
+        success: bool,
+        error: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: String,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+impl Event {
+    fn is_failure(&self) -> bool {
+        matches!(
+            self,
+            Event::ChunkFailed { .. }
+                | Event::InstallResult { success: false, .. }
+                | Event::CombineFinished { success: false, .. }
+                | Event::ExportFinished { success: false, .. }
+                | Event::ReportFinished { success: false, .. }
+                | Event::DiffFinished { success: false, .. }
+        )
+    }
+
+    fn to_text(&self) -> ColoredString {
+        match self {
+            Event::SessionStarted {
+                pod_name,
+                namespace,
+                duration_seconds,
+                ..
+            } => format!(
+                "====> Running continuos pyspy profiling on pod {} in namespace {} for {} seconds",
+                pod_name, namespace, duration_seconds
+            )
+            .green(),
+            Event::ChunkStarted { chunk, .. } => {
+                format!("====> Starting py-spy chunk #{}", chunk).normal()
+            }
+            Event::ChunkCopied { local_path, .. } => {
+                format!("Successfully copied to: {}", local_path).normal()
+            }
+            Event::ChunkFailed { chunk, error } => {
+                format!("====> py-spy chunk #{} failed: {}", chunk, error).red()
+            }
+            Event::InstallResult {
+                already_installed: true,
+                ..
+            } => "====> py-spy is installed in the container.".normal(),
+            Event::InstallResult { success: true, .. } => {
+                "====> py-spy was installed in the container.".normal()
+            }
+            Event::InstallResult { error, .. } => format!(
+                "====> Failed to install py-spy: {}",
+                error.as_deref().unwrap_or("unknown error")
+            )
+            .red(),
+            Event::InputSkipped { error } => format!("====> Skipping {}", error).yellow(),
+            Event::CombineFinished {
+                output,
+                success: true,
+                skipped: 0,
+                ..
+            } => format!("====> Successfuly combined speedscope files to {}", output).green(),
+            Event::CombineFinished {
+                output,
+                success: true,
+                skipped,
+                ..
+            } => format!(
+                "====> Combined speedscope files to {}, skipped {} invalid inputs",
+                output, skipped
+            )
+            .yellow(),
+            Event::CombineFinished { error, .. } => format!(
+                "====> Error combining speedscope files: {}",
+                error.as_deref().unwrap_or("unknown error")
+            )
+            .red(),
+            Event::ExportFinished {
+                output,
+                format,
+                success: true,
+                ..
+            } => format!("====> Successfuly exported {} to {}", format, output).green(),
+            Event::ExportFinished { input, error, .. } => format!(
+                "====> Error exporting {}: {}",
+                input,
+                error.as_deref().unwrap_or("unknown error")
+            )
+            .red(),
+            Event::ReportFinished {
+                inputs,
+                output,
+                success: true,
+                ..
+            } => format!("====> Reported on {} files to {}", inputs, output).green(),
+            Event::ReportFinished { error, .. } => format!(
+                "====> Error building report: {}",
+                error.as_deref().unwrap_or("unknown error")
+            )
+            .red(),
+            Event::DiffFinished {
+                output,
+                success: true,
+                ..
+            } => format!("====> Wrote differential flamegraph to {}", output).green(),
+            Event::DiffFinished { error, .. } => format!(
+                "====> Error comparing profiles: {}",
+                error.as_deref().unwrap_or("unknown error")
+            )
+            .red(),
+        }
+    }
+}
+
+/// Reports progress either as colored text or as JSON lines
+pub struct EventLog {
+    format: LogFormat,
+    /// Everything goes to stderr, because stdout carries the command's output
+    stderr_only: bool,
+    /// Whether a failure event was emitted, so the error isn't reported twice
+    reported_failure: Cell<bool>,
+}
+
+impl EventLog {
+    pub fn new(format: LogFormat) -> Self {
+        Self {
+            format,
+            stderr_only: false,
+            reported_failure: Cell::new(false),
+        }
+    }
+
+    /// Reports on stderr only, for commands that write their result to stdout
+    pub fn on_stderr(self) -> Self {
+        Self {
+            stderr_only: true,
+            ..self
+        }
+    }
+
+    pub fn emit(&self, event: Event) {
+        if event.is_failure() {
+            self.reported_failure.set(true);
+        }
+        match self.format {
+            LogFormat::Text => {
+                let line = event.to_text();
+                if event.is_failure() || self.stderr_only {
+                    eprintln!("{}", line);
+                } else {
+                    println!("{}", line);
+                }
+            }
+            LogFormat::Json => {
+                let record = Record {
+                    timestamp: Utc::now().to_rfc3339(),
+                    event: &event,
+                };
+                let line = serde_json::to_string(&record).expect("events are always serializable");
+                if self.stderr_only {
+                    eprintln!("{}", line);
+                } else {
+                    println!("{}", line);
+                }
+            }
+        }
+    }
+
+    /// Whether an emitted event already reported a failure to the user
+    pub fn reported_failure(&self) -> bool {
+        self.reported_failure.get()
+    }
+
+    /// Free form progress message; kept off stdout in JSON mode
+    pub fn info(&self, message: &str) {
+        match self.format {
+            LogFormat::Text if !self.stderr_only => println!("{}", message),
+            _ => eprintln!("{}", message),
+        }
+    }
+
+    /// Where child processes (kubectl) should write their stdout, so they
+    /// don't interleave with JSON events
+    pub fn child_stdout(&self) -> Stdio {
+        match self.format {
+            LogFormat::Text => Stdio::inherit(),
+            LogFormat::Json => std::io::stderr().into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_serializes_with_name_and_fields() {
+        let event = Event::ChunkCopied {
+            chunk: 2,
+            local_path: "./profiling_results/pyspy_output_1.json".to_string(),
+        };
+        let record = Record {
+            timestamp: "2025-01-01T00:00:00+00:00".to_string(),
+            event: &event,
+        };
+
+        let value = serde_json::to_value(&record).unwrap();
+
+        assert_eq!(value["event"], "chunk_copied");
+        assert_eq!(value["chunk"], 2);
+        assert_eq!(
+            value["local_path"],
+            "./profiling_results/pyspy_output_1.json"
+        );
+        assert_eq!(value["timestamp"], "2025-01-01T00:00:00+00:00");
+    }
+
+    #[test]
+    fn test_failure_events_are_remembered() {
+        let log = EventLog::new(LogFormat::Json).on_stderr();
+        log.emit(Event::ChunkStarted {
+            chunk: 1,
+            remote_file: "pyspy_output_1.json".to_string(),
+        });
+        assert!(!log.reported_failure());
+
+        log.emit(Event::ChunkFailed {
+            chunk: 1,
+            error: "kubectl exited with 1".to_string(),
+        });
+
+        assert!(log.reported_failure());
+    }
+}
@@ -0,0 +1,89 @@
+use crate::collapsed_format::{self, CollapsedOptions};
+use crate::combine_speedscope::{read_valid_speedscope_file, ImportOptions};
+use crate::error::{Error, Result};
+use crate::file_io::{self, Compression};
+use crate::firefox_format;
+use crate::flamegraph::{self, FlamegraphOptions};
+use crate::pprof_format;
+use crate::trace_event_format;
+use flate2::write::GzEncoder;
+use std::io::Write;
+
+/// Formats a speedscope file can be exported to
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Folded stacks (`a;b;c 123`) for flamegraph.pl and inferno
+    Collapsed,
+    /// Speedscope JSON, mainly to convert pprof or collapsed-stack inputs
+    Speedscope,
+    /// Gzipped profile.proto for `go tool pprof`, Grafana and other pprof tooling
+    Pprof,
+    /// Trace Event JSON for Perfetto and chrome://tracing, one track per thread
+    ChromeTrace,
+    /// Processed profile JSON for the Firefox Profiler (profiler.firefox.com), one thread per profile
+    Firefox,
+    /// Self-contained interactive SVG flamegraph, with search, zoom and tooltips
+    Flamegraph,
+    /// The flamegraph upside down, with the roots at the top
+    Icicle,
+}
+
+/// Options of all formats, each format only looks at its own
+#[derive(Debug, Default, Clone)]
+pub struct ExportOptions {
+    /// How the input is read if it is not a speedscope file
+    pub import: ImportOptions,
+    pub collapsed: CollapsedOptions,
+    pub flamegraph: FlamegraphOptions,
+}
+
+/// Converts the profile at `input_path`, in any format combining accepts, to `format` and writes it to `output_path`,
+/// `-` for stdout. The output is compressed when it ends with `.gz` or `.zst`, and an
+/// existing output is only replaced with `force`.
+pub fn entry_point(
+    input_path: &str,
+    output_path: &str,
+    format: ExportFormat,
+    options: &ExportOptions,
+    force: bool,
+) -> Result<()> {
+    file_io::check_overwrite(output_path, force)?;
+    let speedscope = read_valid_speedscope_file(input_path, &options.import)?;
+    let pprof = match format {
+        ExportFormat::Pprof => Some(
+            pprof_format::encode_pprof(&speedscope)
+                .map_err(|reason| Error::invalid_profile(input_path, reason))?,
+        ),
+        _ => None,
+    };
+    let mut output = file_io::create(output_path, force)?;
+    match format {
+        ExportFormat::Collapsed => {
+            collapsed_format::write_collapsed(&speedscope, &options.collapsed, &mut output)
+        }
+        ExportFormat::ChromeTrace => {
+            trace_event_format::write_trace_events(&speedscope, &mut output)
+        }
+        ExportFormat::Flamegraph | ExportFormat::Icicle => {
+            flamegraph::write_flamegraph(&speedscope, &options.flamegraph, &mut output)
+        }
+        ExportFormat::Firefox => firefox_format::write_firefox(&speedscope, &mut output),
+        ExportFormat::Speedscope => {
+            serde_json::to_writer(&mut output, &speedscope).map_err(std::io::Error::from)
+        }
+        ExportFormat::Pprof => {
+            let pprof = pprof.expect("encoded above");
+            // pprof files are gzipped, unless the output extension already compresses them
+            if Compression::from_path(output_path) == Compression::None {
+                let mut encoder = GzEncoder::new(&mut output, flate2::Compression::default());
+                encoder
+                    .write_all(&pprof)
+                    .and_then(|_| encoder.finish().map(|_| ()))
+            } else {
+                output.write_all(&pprof)
+            }
+        }
+    }
+    .and_then(|_| output.finish())
+    .map_err(|e| Error::io(output_path, e))
+}
@@ -0,0 +1,274 @@
+use crate::error::{Error, Result};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// Stands for stdout wherever an output path is expected
+pub const STDOUT: &str = "-";
+
+/// Compression of a file, picked from its extension when writing and from its
+/// first bytes when reading
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// `.gz`
+    Gzip,
+    /// `.zst` or `.zstd`
+    Zstd,
+}
+
+impl Compression {
+    pub fn from_path(path: &str) -> Self {
+        match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst" | "zstd") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+
+    /// `path` without the extension `from_path` detects the compression by
+    pub fn strip_extension(path: &str) -> &str {
+        match Compression::from_path(path) {
+            Compression::None => path,
+            _ => path.rsplit_once('.').map_or(path, |(stem, _)| stem),
+        }
+    }
+
+    /// Detects the compression from the magic bytes at the start of a file
+    pub fn from_magic(start: &[u8]) -> Self {
+        if start.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if start.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Extension added to compressed files, including the dot
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+}
+
+/// Opens a file for reading, decompressing it on the fly if it is gzip or zstd compressed
+pub fn open(path: &str) -> Result<Box<dyn BufRead>> {
+    let file = File::open(path).map_err(|e| Error::io(path, e))?;
+    let mut reader = BufReader::new(file);
+    let start = reader.fill_buf().map_err(|e| Error::io(path, e))?;
+    Ok(match Compression::from_magic(start) {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+        Compression::Zstd => Box::new(BufReader::new(
+            zstd::Decoder::with_buffer(reader).map_err(|e| Error::io(path, e))?,
+        )),
+    })
+}
+
+/// Compresses the file at `path` next to it, named with the compression's extension,
+/// and removes the original. Returns the path of the compressed file.
+pub fn compress_file(path: &str, compression: Compression) -> Result<String> {
+    if compression == Compression::None {
+        return Ok(path.to_string());
+    }
+    let compressed_path = format!("{}{}", path, compression.extension());
+    let mut input = File::open(path).map_err(|e| Error::io(path, e))?;
+    let mut output = create(&compressed_path, false)?;
+    io::copy(&mut input, &mut output)
+        .and_then(|_| output.finish())
+        .map_err(|e| Error::io(&compressed_path, e))?;
+    std::fs::remove_file(path).map_err(|e| Error::io(path, e))?;
+    Ok(compressed_path)
+}
+
+/// A file or stdout being written, compressed according to its extension.
+/// `finish` must be called to flush it and write the end of compressed streams.
+pub enum Output {
+    Plain(BufWriter<Box<dyn Write>>),
+    Gzip(GzEncoder<BufWriter<Box<dyn Write>>>),
+    Zstd(zstd::Encoder<'static, BufWriter<Box<dyn Write>>>),
+}
+
+impl Output {
+    pub fn finish(self) -> io::Result<()> {
+        let mut inner = match self {
+            Output::Plain(inner) => inner,
+            Output::Gzip(encoder) => encoder.finish()?,
+            Output::Zstd(encoder) => encoder.finish()?,
+        };
+        inner.flush()
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Plain(inner) => inner.write(buf),
+            Output::Gzip(encoder) => encoder.write(buf),
+            Output::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Plain(inner) => inner.flush(),
+            Output::Gzip(encoder) => encoder.flush(),
+            Output::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Fails if `path` exists and may not be overwritten, so long running work can check
+/// before it starts instead of failing at the end
+pub fn check_overwrite(path: &str, force: bool) -> Result<()> {
+    if path != STDOUT && !force && Path::new(path).exists() {
+        return Err(Error::io(
+            path,
+            io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "output already exists, use --force to overwrite it",
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Opens `path` for writing, `-` is stdout. Missing parent directories are created,
+/// and an existing file is only replaced with `force`.
+pub fn create(path: &str, force: bool) -> Result<Output> {
+    let writer: Box<dyn Write> = if path == STDOUT {
+        Box::new(io::stdout())
+    } else {
+        check_overwrite(path, force)?;
+        if let Some(parent) = Path::new(path).parent() {
+            std::fs::create_dir_all(parent).map_err(|e| Error::io(path, e))?;
+        }
+        let file = if force {
+            File::create(path)
+        } else {
+            OpenOptions::new().write(true).create_new(true).open(path)
+        };
+        Box::new(file.map_err(|e| Error::io(path, e))?)
+    };
+    let writer = BufWriter::new(writer);
+    Ok(match Compression::from_path(path) {
+        Compression::None => Output::Plain(writer),
+        Compression::Gzip => Output::Gzip(GzEncoder::new(writer, flate2::Compression::default())),
+        Compression::Zstd => {
+            Output::Zstd(zstd::Encoder::new(writer, 0).map_err(|e| Error::io(path, e))?)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn write_all(path: &str, force: bool, content: &[u8]) -> Result<()> {
+        let mut output = create(path, force)?;
+        output.write_all(content).unwrap();
+        output.finish().map_err(|e| Error::io(path, e))
+    }
+
+    #[test]
+    fn test_creates_missing_parent_directories() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("results/run_1/combined.json");
+
+        write_all(path.to_str().unwrap(), false, b"{}").unwrap();
+
+        assert_eq!(std::fs::read(path).unwrap(), b"{}");
+    }
+
+    #[test]
+    fn test_existing_output_needs_force() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("combined.json");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "old").unwrap();
+
+        let result = write_all(path, false, b"new");
+
+        assert!(matches!(result, Err(Error::Io { .. })));
+        assert_eq!(std::fs::read(path).unwrap(), b"old");
+        write_all(path, true, b"new").unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"new");
+    }
+
+    #[test]
+    fn test_compression_from_extension() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let gzip_path = temp_dir.path().join("combined.json.gz");
+        let zstd_path = temp_dir.path().join("combined.json.zst");
+
+        write_all(gzip_path.to_str().unwrap(), false, b"{}").unwrap();
+        write_all(zstd_path.to_str().unwrap(), false, b"{}").unwrap();
+
+        let mut gzip = String::new();
+        flate2::read::GzDecoder::new(File::open(gzip_path).unwrap())
+            .read_to_string(&mut gzip)
+            .unwrap();
+        assert_eq!(gzip, "{}");
+        assert_eq!(
+            zstd::decode_all(File::open(zstd_path).unwrap()).unwrap(),
+            b"{}"
+        );
+        assert_eq!(Compression::from_path("combined.json"), Compression::None);
+    }
+
+    #[test]
+    fn test_open_detects_compression_by_content() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        for (name, compression) in [
+            ("plain.json", Compression::None),
+            ("chunk.json.gz", Compression::Gzip),
+            ("chunk.json.zst", Compression::Zstd),
+            // misnamed files are still read by their content
+            ("mislabeled.json", Compression::Gzip),
+        ] {
+            let path = temp_dir.path().join(name);
+            let path = path.to_str().unwrap();
+            let encoded = match compression {
+                Compression::None => b"{}".to_vec(),
+                Compression::Gzip => {
+                    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                    encoder.write_all(b"{}").unwrap();
+                    encoder.finish().unwrap()
+                }
+                Compression::Zstd => zstd::encode_all(&b"{}"[..], 0).unwrap(),
+            };
+            std::fs::write(path, encoded).unwrap();
+
+            let mut content = String::new();
+            open(path).unwrap().read_to_string(&mut content).unwrap();
+
+            assert_eq!(content, "{}", "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_compress_file_replaces_original() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("pyspy_output_1.json");
+        std::fs::write(&path, "{\"profiles\": []}").unwrap();
+
+        let compressed = compress_file(path.to_str().unwrap(), Compression::Zstd).unwrap();
+
+        assert!(compressed.ends_with("pyspy_output_1.json.zst"));
+        assert!(!path.exists());
+        let mut content = String::new();
+        open(&compressed)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "{\"profiles\": []}");
+    }
+}
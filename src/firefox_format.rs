@@ -0,0 +1,420 @@
+use crate::speedscope_format::{seconds_per_unit, FrameKey, SampledProfile, Speedscope};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// Version of the processed format written, older versions are upgraded by the profiler
+const PREPROCESSED_PROFILE_VERSION: u32 = 47;
+/// Version of the Gecko format the processed format was derived from
+const GECKO_PROFILE_VERSION: u32 = 27;
+/// Index of the only category, every frame is Python code
+const PYTHON_CATEGORY: usize = 0;
+
+/// A profile in the Firefox Profiler's processed format
+/// (github.com/firefox-devtools/profiler/blob/main/docs-developer/processed-profile-format.md)
+#[derive(Serialize, Debug)]
+pub struct FirefoxProfile {
+    pub meta: Meta,
+    pub libs: Vec<serde_json::Value>,
+    pub pages: Vec<serde_json::Value>,
+    pub counters: Vec<serde_json::Value>,
+    pub threads: Vec<Thread>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Meta {
+    /// Sampling interval in milliseconds
+    pub interval: f64,
+    /// Milliseconds since the epoch that sample times are relative to
+    pub start_time: f64,
+    pub process_type: u32,
+    pub product: String,
+    pub stackwalk: u32,
+    pub version: u32,
+    pub preprocessed_profile_version: u32,
+    pub symbolicated: bool,
+    pub categories: Vec<Category>,
+    pub marker_schema: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Category {
+    pub name: String,
+    pub color: String,
+    pub subcategories: Vec<String>,
+}
+
+/// One thread with its own tables, indexes point into the tables of the same thread
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Thread {
+    pub name: String,
+    pub process_type: String,
+    pub process_startup_time: f64,
+    pub process_shutdown_time: Option<f64>,
+    pub register_time: f64,
+    pub unregister_time: Option<f64>,
+    pub paused_ranges: Vec<serde_json::Value>,
+    pub is_main_thread: bool,
+    pub pid: String,
+    pub tid: usize,
+    pub samples: SamplesTable,
+    pub markers: MarkersTable,
+    pub stack_table: StackTable,
+    pub frame_table: FrameTable,
+    pub func_table: FuncTable,
+    pub resource_table: ResourceTable,
+    pub native_symbols: NativeSymbols,
+    pub string_array: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SamplesTable {
+    pub length: usize,
+    /// `None` for samples where no Python code ran
+    pub stack: Vec<Option<usize>>,
+    /// Start of every sample in milliseconds
+    pub time: Vec<f64>,
+    /// Length of every sample in milliseconds
+    pub weight: Vec<f64>,
+    pub weight_type: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct MarkersTable {
+    pub length: usize,
+    pub category: Vec<usize>,
+    pub data: Vec<serde_json::Value>,
+    pub end_time: Vec<f64>,
+    pub name: Vec<usize>,
+    pub phase: Vec<u8>,
+    pub start_time: Vec<f64>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct StackTable {
+    pub length: usize,
+    pub frame: Vec<usize>,
+    pub prefix: Vec<Option<usize>>,
+    pub category: Vec<usize>,
+    pub subcategory: Vec<usize>,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct FrameTable {
+    pub length: usize,
+    pub address: Vec<i64>,
+    pub inline_depth: Vec<u32>,
+    pub category: Vec<usize>,
+    pub subcategory: Vec<usize>,
+    pub func: Vec<usize>,
+    pub native_symbol: Vec<Option<usize>>,
+    #[serde(rename = "innerWindowID")]
+    pub inner_window_id: Vec<Option<u64>>,
+    pub implementation: Vec<Option<usize>>,
+    pub line: Vec<Option<u32>>,
+    pub column: Vec<Option<u32>>,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct FuncTable {
+    pub length: usize,
+    /// Index into the string array
+    pub name: Vec<usize>,
+    #[serde(rename = "isJS")]
+    pub is_js: Vec<bool>,
+    #[serde(rename = "relevantForJS")]
+    pub relevant_for_js: Vec<bool>,
+    pub resource: Vec<i64>,
+    /// Index into the string array
+    pub file_name: Vec<Option<usize>>,
+    pub line_number: Vec<Option<u32>>,
+    pub column_number: Vec<Option<u32>>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ResourceTable {
+    pub length: usize,
+    pub lib: Vec<usize>,
+    pub name: Vec<usize>,
+    pub host: Vec<Option<usize>>,
+    #[serde(rename = "type")]
+    pub r#type: Vec<u32>,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct NativeSymbols {
+    pub length: usize,
+    pub lib_index: Vec<usize>,
+    pub address: Vec<i64>,
+    pub name: Vec<usize>,
+    pub function_size: Vec<Option<u32>>,
+}
+
+/// Interns strings, functions, frames and stacks of one thread
+#[derive(Default)]
+struct ThreadTables {
+    thread: Thread,
+    strings: HashMap<String, usize>,
+    funcs: HashMap<(String, Option<String>), usize>,
+    frames: HashMap<i32, usize>,
+    stacks: HashMap<(Option<usize>, usize), usize>,
+}
+
+impl ThreadTables {
+    fn string(&mut self, value: &str) -> usize {
+        if let Some(index) = self.strings.get(value) {
+            return *index;
+        }
+        let strings = &mut self.thread.string_array;
+        strings.push(value.to_string());
+        self.strings.insert(value.to_string(), strings.len() - 1);
+        strings.len() - 1
+    }
+
+    fn func(&mut self, key: &FrameKey) -> usize {
+        let func_key = (key.name.clone(), key.file.clone());
+        if let Some(index) = self.funcs.get(&func_key) {
+            return *index;
+        }
+        let name = self.string(&key.name);
+        let file_name = key.file.as_deref().map(|file| self.string(file));
+        let funcs = &mut self.thread.func_table;
+        funcs.name.push(name);
+        funcs.is_js.push(false);
+        funcs.relevant_for_js.push(false);
+        funcs.resource.push(-1);
+        funcs.file_name.push(file_name);
+        funcs.line_number.push(None);
+        funcs.column_number.push(None);
+        funcs.length += 1;
+        self.funcs.insert(func_key, funcs.length - 1);
+        funcs.length - 1
+    }
+
+    fn frame(&mut self, index: i32, key: &FrameKey) -> usize {
+        if let Some(frame) = self.frames.get(&index) {
+            return *frame;
+        }
+        let func = self.func(key);
+        let frames = &mut self.thread.frame_table;
+        frames.address.push(-1);
+        frames.inline_depth.push(0);
+        frames.category.push(PYTHON_CATEGORY);
+        frames.subcategory.push(0);
+        frames.func.push(func);
+        frames.native_symbol.push(None);
+        frames.inner_window_id.push(None);
+        frames.implementation.push(None);
+        frames.line.push(key.line);
+        frames.column.push(key.col);
+        frames.length += 1;
+        self.frames.insert(index, frames.length - 1);
+        frames.length - 1
+    }
+
+    fn stack(&mut self, prefix: Option<usize>, frame: usize) -> usize {
+        if let Some(stack) = self.stacks.get(&(prefix, frame)) {
+            return *stack;
+        }
+        let stacks = &mut self.thread.stack_table;
+        stacks.frame.push(frame);
+        stacks.prefix.push(prefix);
+        stacks.category.push(PYTHON_CATEGORY);
+        stacks.subcategory.push(0);
+        stacks.length += 1;
+        self.stacks.insert((prefix, frame), stacks.length - 1);
+        stacks.length - 1
+    }
+}
+
+fn thread(profile: &SampledProfile, tid: usize, frame_keys: &[FrameKey], millis: f64) -> Thread {
+    let mut tables = ThreadTables::default();
+    let mut at = profile.start_value;
+    for (sample, weight) in profile.samples.iter().zip(&profile.weights) {
+        let mut stack = None;
+        for frame_index in sample {
+            let frame = tables.frame(*frame_index, &frame_keys[*frame_index as usize]);
+            stack = Some(tables.stack(stack, frame));
+        }
+        let samples = &mut tables.thread.samples;
+        samples.stack.push(stack);
+        samples.time.push(at * millis);
+        samples.weight.push(weight * millis);
+        samples.length += 1;
+        at += weight;
+    }
+    Thread {
+        name: profile.name.clone(),
+        process_type: "default".to_string(),
+        is_main_thread: tid == 0,
+        pid: "1".to_string(),
+        tid,
+        samples: SamplesTable {
+            weight_type: "tracing-ms".to_string(),
+            ..tables.thread.samples
+        },
+        ..tables.thread
+    }
+}
+
+/// Builds a Firefox Profiler profile from a valid speedscope file.
+///
+/// Every profile becomes a thread. Samples are laid end to end from the profile's start
+/// value, so each sample is timestamped with the sum of the weights before it and keeps
+/// its weight as a duration in milliseconds. Frames become a func per name and file and a
+/// frame per line. All profiles must be measured in time.
+pub fn to_firefox(speedscope: &Speedscope) -> Result<FirefoxProfile, String> {
+    let frame_keys: Vec<FrameKey> = speedscope.shared.frames.iter().map(|f| f.key()).collect();
+    let mut threads = Vec::with_capacity(speedscope.profiles.len());
+    let mut sample_count = 0;
+    let mut sampled_millis = 0.0;
+    for (tid, profile) in speedscope.profiles.iter().enumerate() {
+        let profile = profile.to_sampled()?;
+        let millis = seconds_per_unit(&profile.unit)
+            .map(|seconds| seconds * 1e3)
+            .ok_or_else(|| {
+                format!(
+                    "profile {} is in {:?}, which has no time axis for the Firefox Profiler",
+                    profile.name, profile.unit
+                )
+            })?;
+        sample_count += profile.samples.len();
+        sampled_millis += profile.weights.iter().sum::<f64>() * millis;
+        threads.push(thread(&profile, tid, &frame_keys, millis));
+    }
+    let interval = if sample_count > 0 {
+        sampled_millis / sample_count as f64
+    } else {
+        1.0
+    };
+    Ok(FirefoxProfile {
+        meta: Meta {
+            interval,
+            start_time: 0.0,
+            process_type: 0,
+            product: speedscope
+                .name
+                .clone()
+                .unwrap_or_else(|| "python".to_string()),
+            stackwalk: 0,
+            version: GECKO_PROFILE_VERSION,
+            preprocessed_profile_version: PREPROCESSED_PROFILE_VERSION,
+            symbolicated: true,
+            categories: vec![Category {
+                name: "Python".to_string(),
+                color: "yellow".to_string(),
+                subcategories: vec!["Other".to_string()],
+            }],
+            marker_schema: Vec::new(),
+        },
+        libs: Vec::new(),
+        pages: Vec::new(),
+        counters: Vec::new(),
+        threads,
+    })
+}
+
+/// Writes a valid speedscope file in the Firefox Profiler's processed format, see `to_firefox`
+pub fn write_firefox(speedscope: &Speedscope, out: &mut impl Write) -> io::Result<()> {
+    let profile = to_firefox(speedscope).map_err(io::Error::other)?;
+    serde_json::to_writer(out, &profile).map_err(io::Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::speedscope_format::{Extra, Frame, Profile, Shared};
+
+    fn frame(name: &str, line: u32) -> Frame {
+        Frame {
+            name: name.to_string(),
+            file: Some("app.py".to_string()),
+            line: Some(line),
+            ..Frame::default()
+        }
+    }
+
+    fn input(unit: &str) -> Speedscope {
+        let profile = |name: &str| {
+            Profile::Sampled(SampledProfile {
+                name: name.to_string(),
+                unit: unit.to_string(),
+                start_value: 0.5,
+                end_value: 3.5,
+                samples: vec![vec![0, 1], vec![0, 2], vec![]],
+                weights: vec![1.0, 1.0, 1.0],
+                ..SampledProfile::default()
+            })
+        };
+        Speedscope {
+            profiles: vec![profile("MainThread"), profile("Worker")],
+            shared: Shared {
+                // two lines of one function share a func but not a frame
+                frames: vec![frame("main", 1), frame("handle", 5), frame("handle", 7)],
+                extra: Extra::new(),
+            },
+            schema: String::new(),
+            exporter: None,
+            name: None,
+            active_profile_index: None,
+            extra: Extra::new(),
+        }
+    }
+
+    #[test]
+    fn test_profiles_become_threads_with_tables() {
+        let firefox = to_firefox(&input("seconds")).unwrap();
+
+        assert_eq!(firefox.threads.len(), 2);
+        let thread = &firefox.threads[0];
+        assert_eq!(thread.name, "MainThread");
+        assert!(thread.is_main_thread);
+        assert!(!firefox.threads[1].is_main_thread);
+        assert_eq!(thread.func_table.length, 2);
+        assert_eq!(thread.frame_table.length, 3);
+        assert_eq!(thread.frame_table.func, vec![0, 1, 1]);
+        assert_eq!(thread.frame_table.line, vec![Some(1), Some(5), Some(7)]);
+        assert_eq!(thread.stack_table.prefix, vec![None, Some(0), Some(0)]);
+        assert_eq!(thread.samples.stack, vec![Some(1), Some(2), None]);
+        let name = thread.func_table.name[1];
+        assert_eq!(thread.string_array[name], "handle");
+    }
+
+    #[test]
+    fn test_sample_times_come_from_weights() {
+        let firefox = to_firefox(&input("milliseconds")).unwrap();
+
+        let samples = &firefox.threads[0].samples;
+        assert_eq!(samples.time, vec![0.5, 1.5, 2.5]);
+        assert_eq!(samples.weight, vec![1.0, 1.0, 1.0]);
+        assert_eq!(samples.weight_type, "tracing-ms");
+        assert_eq!(firefox.meta.interval, 1.0);
+    }
+
+    #[test]
+    fn test_serializes_with_processed_format_names() {
+        let json = serde_json::to_value(to_firefox(&input("seconds")).unwrap()).unwrap();
+
+        assert_eq!(json["meta"]["preprocessedProfileVersion"], 47);
+        let thread = &json["threads"][0];
+        assert_eq!(thread["samples"]["time"][1], 1500.0);
+        assert_eq!(
+            thread["frameTable"]["innerWindowID"][0],
+            serde_json::Value::Null
+        );
+        assert_eq!(thread["funcTable"]["isJS"][0], false);
+        assert_eq!(thread["stringArray"][0], "main");
+    }
+
+    #[test]
+    fn test_profiles_without_time_unit_are_rejected() {
+        assert!(to_firefox(&input("none")).unwrap_err().contains("\"none\""));
+    }
+}
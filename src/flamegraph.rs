@@ -0,0 +1,766 @@
+use crate::frame_normalization::module_name;
+use crate::speedscope_format::{seconds_per_unit, Speedscope};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+const WIDTH: f64 = 1200.0;
+const SIDE_PADDING: f64 = 10.0;
+const FRAME_HEIGHT: f64 = 16.0;
+const HEADER_HEIGHT: f64 = 40.0;
+const FOOTER_HEIGHT: f64 = 30.0;
+/// Approximate width of a character in the 12px font, to fit labels in their frames
+const CHAR_WIDTH: f64 = 7.0;
+/// Frames narrower than this are left out, they can't be seen or hovered anyway
+const MIN_FRAME_WIDTH: f64 = 0.1;
+
+/// Which way the stacks grow
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Direction {
+    /// Roots at the bottom, the classic flamegraph
+    #[default]
+    Up,
+    /// Roots at the top, an icicle graph
+    Down,
+}
+
+/// Options for rendering a flamegraph
+#[derive(Debug, Clone, Default)]
+pub struct FlamegraphOptions {
+    pub direction: Direction,
+    /// Title shown above the graph, the speedscope name or "Flame Graph" if not given
+    pub title: Option<String>,
+    /// Start every stack with the thread (profile) name
+    pub thread_names: bool,
+}
+
+/// A frame and everything called from it, merged over all samples
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FlameNode {
+    pub name: String,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    /// Node holding the stacks of one thread, see `FlamegraphOptions::thread_names`
+    pub is_thread: bool,
+    /// Summed weight of the samples going through this node, in seconds for time units
+    pub value: f64,
+    pub samples: usize,
+    /// Value of the same node in the profile compared against, for differential graphs
+    pub baseline: f64,
+    /// Keyed by name first, so callees are drawn in alphabetical order
+    pub children: BTreeMap<(String, Option<String>, Option<u32>), FlameNode>,
+}
+
+impl FlameNode {
+    /// Sets `baseline` of this node and everything below it from the matching nodes of `base`
+    pub fn set_baseline(&mut self, base: Option<&FlameNode>) {
+        self.baseline = base.map_or(0.0, |base| base.value);
+        for (key, child) in &mut self.children {
+            child.set_baseline(base.and_then(|base| base.children.get(key)));
+        }
+    }
+
+    fn child(&mut self, name: &str, file: Option<&str>, line: Option<u32>) -> &mut FlameNode {
+        let key = (name.to_string(), file.map(str::to_string), line);
+        self.children.entry(key).or_insert_with(|| FlameNode {
+            name: name.to_string(),
+            file: file.map(str::to_string),
+            line,
+            ..FlameNode::default()
+        })
+    }
+}
+
+/// Stacks of a speedscope file merged into one tree
+#[derive(Debug, Clone)]
+pub struct FlameGraph {
+    pub root: FlameNode,
+    /// Unit of the node values, `seconds` when all profiles are measured in time
+    pub unit: String,
+}
+
+impl FlameGraph {
+    /// Merges the samples of every profile of a valid speedscope file.
+    /// Time weights are converted to seconds, other units must all be the same.
+    /// Empty samples are left out.
+    pub fn from_speedscope(speedscope: &Speedscope, thread_names: bool) -> Result<Self, String> {
+        let sampled = speedscope
+            .profiles
+            .iter()
+            .map(|profile| profile.to_sampled())
+            .collect::<Result<Vec<_>, _>>()?;
+        let all_time = sampled
+            .iter()
+            .all(|profile| seconds_per_unit(&profile.unit).is_some());
+        let unit = match sampled.first() {
+            Some(_) if all_time => "seconds".to_string(),
+            Some(profile) => profile.unit.clone(),
+            None => "none".to_string(),
+        };
+        if let Some(other) = sampled
+            .iter()
+            .find(|profile| !all_time && profile.unit != unit)
+        {
+            return Err(format!(
+                "profile {} is in {:?}, which can't be summed with {:?}",
+                other.name, other.unit, unit
+            ));
+        }
+        let mut root = FlameNode {
+            name: "all".to_string(),
+            ..FlameNode::default()
+        };
+        for profile in &sampled {
+            let scale = if all_time {
+                seconds_per_unit(&profile.unit).unwrap_or(1.0)
+            } else {
+                1.0
+            };
+            for (sample, weight) in profile.samples.iter().zip(&profile.weights) {
+                if sample.is_empty() {
+                    continue;
+                }
+                let value = weight * scale;
+                root.value += value;
+                root.samples += 1;
+                let mut node = &mut root;
+                if thread_names {
+                    node = node.child(&profile.name, None, None);
+                    node.is_thread = true;
+                    node.value += value;
+                    node.samples += 1;
+                }
+                for index in sample {
+                    let frame = &speedscope.shared.frames[*index as usize];
+                    node = node.child(&frame.name, frame.file.as_deref(), frame.line);
+                    node.value += value;
+                    node.samples += 1;
+                }
+            }
+        }
+        Ok(FlameGraph { root, unit })
+    }
+}
+
+/// Escapes text for SVG content and attribute values
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The label shortened to fit in `width` pixels, the same way the embedded script does
+fn fit_label(label: &str, width: f64) -> String {
+    let chars = ((width - 6.0) / CHAR_WIDTH).floor();
+    if chars < 3.0 {
+        return String::new();
+    }
+    let chars = chars as usize;
+    if label.chars().count() <= chars {
+        label.to_string()
+    } else {
+        let mut fitted: String = label.chars().take(chars - 2).collect();
+        fitted.push_str("..");
+        fitted
+    }
+}
+
+/// FNV-1a, stable across runs and platforms unlike the std hasher
+fn stable_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// `#rrggbb` for a hue in degrees and saturation and lightness between 0 and 1
+fn hsl_to_hex(hue: f64, saturation: f64, lightness: f64) -> String {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let h = hue / 60.0;
+    let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    let channel = |value: f64| ((value + m) * 255.0).round() as u8;
+    format!("#{:02x}{:02x}{:02x}", channel(r), channel(g), channel(b))
+}
+
+/// Color of a frame, the same for every frame of a Python module in every graph
+pub fn module_color(file: Option<&str>) -> String {
+    match file {
+        Some(file) => {
+            let hash = stable_hash(&module_name(file));
+            let hue = (hash % 360) as f64;
+            let saturation = 0.55 + ((hash >> 16) % 20) as f64 / 100.0;
+            let lightness = 0.62 + ((hash >> 32) % 10) as f64 / 100.0;
+            hsl_to_hex(hue, saturation, lightness)
+        }
+        None => "#c8c8c8".to_string(),
+    }
+}
+
+/// Color of a node in a differential graph: red when it grew, blue when it shrank,
+/// more saturated the larger the change relative to its size
+fn diff_color(node: &FlameNode) -> String {
+    let largest = node.value.max(node.baseline);
+    if largest <= 0.0 {
+        return "#f0f0f0".to_string();
+    }
+    let change = (node.value - node.baseline) / largest;
+    let hue = if change >= 0.0 { 0.0 } else { 220.0 };
+    hsl_to_hex(hue, 0.8, 0.94 - 0.44 * change.abs())
+}
+
+/// Formats a node value for tooltips
+pub fn format_value(value: f64, unit: &str) -> String {
+    match unit {
+        "seconds" => format!("{:.3} s", value),
+        "none" => format!("{}", value),
+        unit => format!("{} {}", value, unit),
+    }
+}
+
+fn tooltip(node: &FlameNode, total: f64, unit: &str) -> String {
+    let location = match (&node.file, node.line) {
+        (Some(file), Some(line)) => format!(" ({}:{})", file, line),
+        (Some(file), None) => format!(" ({})", file),
+        (None, _) => String::new(),
+    };
+    let percent = if total > 0.0 {
+        node.value / total * 100.0
+    } else {
+        0.0
+    };
+    format!(
+        "{}{}: {:.2}%, {}, {} samples",
+        node.name,
+        location,
+        percent,
+        format_value(node.value, unit),
+        node.samples
+    )
+}
+
+fn diff_tooltip(node: &FlameNode, total: f64, unit: &str) -> String {
+    let location = match (&node.file, node.line) {
+        (Some(file), Some(line)) => format!(" ({}:{})", file, line),
+        (Some(file), None) => format!(" ({})", file),
+        (None, _) => String::new(),
+    };
+    let percent = |value: f64| {
+        if total > 0.0 {
+            value / total * 100.0
+        } else {
+            0.0
+        }
+    };
+    format!(
+        "{}{}: {:.2}% -> {:.2}% ({:+.2}%), {} -> {}",
+        node.name,
+        location,
+        percent(node.baseline),
+        percent(node.value),
+        percent(node.value - node.baseline),
+        format_value(node.baseline, unit),
+        format_value(node.value, unit),
+    )
+}
+
+/// A node placed in the graph, `x` and `width` are fractions of the total
+pub struct PlacedFrame<'a> {
+    pub node: &'a FlameNode,
+    pub x: f64,
+    pub width: f64,
+    pub depth: usize,
+}
+
+/// Places the nodes wide enough to be drawn, parents before their children
+pub fn layout(root: &FlameNode) -> Vec<PlacedFrame<'_>> {
+    fn place<'a>(
+        node: &'a FlameNode,
+        x: f64,
+        depth: usize,
+        total: f64,
+        frames: &mut Vec<PlacedFrame<'a>>,
+    ) {
+        let width = node.value / total;
+        if width * (WIDTH - 2.0 * SIDE_PADDING) < MIN_FRAME_WIDTH {
+            return;
+        }
+        frames.push(PlacedFrame {
+            node,
+            x,
+            width,
+            depth,
+        });
+        let mut child_x = x;
+        for child in node.children.values() {
+            place(child, child_x, depth + 1, total, frames);
+            child_x += child.value / total;
+        }
+    }
+
+    let mut frames = Vec::new();
+    if root.value > 0.0 {
+        place(root, 0.0, 0, root.value, &mut frames);
+    }
+    frames
+}
+
+/// Writes the frames as an interactive SVG: click a frame to zoom into it, search frames
+/// by regular expression with the Search button or Ctrl+F, and hover for details.
+/// Everything is inline so the file works offline. `fill` gives the color of each frame.
+pub fn write_frames_svg(
+    frames: &[PlacedFrame],
+    title: &str,
+    subtitle: &str,
+    direction: Direction,
+    tooltip_of: impl Fn(&FlameNode) -> String,
+    fill: impl Fn(&FlameNode) -> String,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let max_depth = frames.iter().map(|frame| frame.depth).max().unwrap_or(0);
+    let graph_width = WIDTH - 2.0 * SIDE_PADDING;
+    let height = HEADER_HEIGHT + (max_depth + 1) as f64 * FRAME_HEIGHT + FOOTER_HEIGHT;
+
+    writeln!(out, r#"<?xml version="1.0" standalone="no"?>"#)?;
+    writeln!(
+        out,
+        r#"<svg version="1.1" width="{w}" height="{h}" viewBox="0 0 {w} {h}" xmlns="http://www.w3.org/2000/svg" onload="init(evt)">"#,
+        w = WIDTH,
+        h = height
+    )?;
+    writeln!(out, "<style>{}</style>", STYLE)?;
+    writeln!(
+        out,
+        r#"<script type="text/ecmascript"><![CDATA[{}]]></script>"#,
+        SCRIPT
+    )?;
+    writeln!(
+        out,
+        r##"<rect x="0" y="0" width="100%" height="100%" fill="#f8f8f8"/>"##
+    )?;
+    writeln!(
+        out,
+        r#"<text id="title" x="{}" y="20">{}</text>"#,
+        WIDTH / 2.0,
+        escape(title)
+    )?;
+    writeln!(
+        out,
+        r#"<text id="subtitle" x="{}" y="34">{}</text>"#,
+        WIDTH / 2.0,
+        escape(subtitle)
+    )?;
+    writeln!(
+        out,
+        r#"<text id="reset" class="button hide" x="{}" y="20">Reset Zoom</text>"#,
+        SIDE_PADDING
+    )?;
+    writeln!(
+        out,
+        r#"<text id="search" class="button" x="{}" y="20">Search</text>"#,
+        WIDTH - SIDE_PADDING
+    )?;
+    writeln!(
+        out,
+        r#"<text id="details" x="{}" y="{}"> </text>"#,
+        SIDE_PADDING,
+        height - 10.0
+    )?;
+    writeln!(
+        out,
+        r#"<text id="matched" x="{}" y="{}"> </text>"#,
+        WIDTH - SIDE_PADDING,
+        height - 10.0
+    )?;
+    writeln!(out, r#"<g id="frames">"#)?;
+    for frame in frames {
+        let depth = frame.depth as f64;
+        let y = match direction {
+            Direction::Up => HEADER_HEIGHT + (max_depth as f64 - depth) * FRAME_HEIGHT,
+            Direction::Down => HEADER_HEIGHT + depth * FRAME_HEIGHT,
+        };
+        let x = SIDE_PADDING + frame.x * graph_width;
+        let width = frame.width * graph_width;
+        let color = fill(frame.node);
+        writeln!(
+            out,
+            r#"<g class="f" data-x="{}" data-w="{}" data-d="{}" data-l="{}" data-s="{}" data-c="{}"><title>{}</title><rect x="{:.2}" y="{}" width="{:.2}" height="{}" rx="2" fill="{}"/><text x="{:.2}" y="{}">{}</text></g>"#,
+            frame.x,
+            frame.width,
+            frame.depth,
+            escape(&frame.node.name),
+            escape(frame.node.file.as_deref().unwrap_or("")),
+            color,
+            escape(&tooltip_of(frame.node)),
+            x,
+            y,
+            width,
+            FRAME_HEIGHT - 1.0,
+            color,
+            x + 3.0,
+            y + FRAME_HEIGHT - 4.5,
+            escape(&fit_label(&frame.node.name, width)),
+        )?;
+    }
+    writeln!(out, "</g>")?;
+    writeln!(out, "</svg>")
+}
+
+/// Renders a valid speedscope file as an interactive flamegraph or icicle SVG,
+/// with frames colored by their Python module
+pub fn write_flamegraph(
+    speedscope: &Speedscope,
+    options: &FlamegraphOptions,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let graph =
+        FlameGraph::from_speedscope(speedscope, options.thread_names).map_err(io::Error::other)?;
+    let title = options
+        .title
+        .clone()
+        .or_else(|| speedscope.name.clone())
+        .unwrap_or_else(|| match options.direction {
+            Direction::Up => "Flame Graph".to_string(),
+            Direction::Down => "Icicle Graph".to_string(),
+        });
+    let subtitle = format!(
+        "{} samples, {}",
+        graph.root.samples,
+        format_value(graph.root.value, &graph.unit)
+    );
+    let total = graph.root.value;
+    write_frames_svg(
+        &layout(&graph.root),
+        &title,
+        &subtitle,
+        options.direction,
+        |node| tooltip(node, total, &graph.unit),
+        |node| {
+            if node.is_thread || std::ptr::eq(node, &graph.root) {
+                "#b4c8dc".to_string()
+            } else {
+                module_color(node.file.as_deref())
+            }
+        },
+        out,
+    )
+}
+
+/// Renders a differential flamegraph: the graph has the shape of `graph`, frames are
+/// colored by how their value changed from their `baseline`, and tooltips show both.
+/// `total` is the value percentages are relative to.
+pub fn write_differential_flamegraph(
+    graph: &FlameGraph,
+    total: f64,
+    title: &str,
+    subtitle: &str,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    write_frames_svg(
+        &layout(&graph.root),
+        title,
+        subtitle,
+        Direction::Up,
+        |node| diff_tooltip(node, total, &graph.unit),
+        diff_color,
+        out,
+    )
+}
+
+const STYLE: &str = r#"
+text { font-family: Verdana, sans-serif; font-size: 12px; fill: #000; }
+#title { font-size: 17px; text-anchor: middle; }
+#subtitle { fill: #666; text-anchor: middle; }
+#search, #matched { text-anchor: end; }
+.button { cursor: pointer; fill: #336; }
+.button:hover { text-decoration: underline; }
+.hide { display: none; }
+.f { cursor: pointer; }
+.f:hover rect { stroke: #000; stroke-width: 0.5; }
+.f text { pointer-events: none; }
+"#;
+
+const SCRIPT: &str = r##"
+var svgWidth, graphWidth, frames, searchRe = null;
+var CHAR_WIDTH = 7, PADDING = 10;
+function init(evt) {
+  svgWidth = parseFloat(document.documentElement.getAttribute("width"));
+  graphWidth = svgWidth - 2 * PADDING;
+  frames = Array.prototype.slice.call(document.querySelectorAll("#frames > g"));
+  frames.forEach(function (g) {
+    g.addEventListener("click", function () { zoom(g); });
+    g.addEventListener("mouseover", function () { details(g.querySelector("title").textContent); });
+    g.addEventListener("mouseout", function () { details(" "); });
+  });
+  document.getElementById("reset").addEventListener("click", function () { zoom(null); });
+  document.getElementById("search").addEventListener("click", function () {
+    if (searchRe) { clearSearch(); } else { search(); }
+  });
+  window.addEventListener("keydown", function (e) {
+    if ((e.ctrlKey || e.metaKey) && e.key === "f") { e.preventDefault(); search(); }
+    if (e.key === "Escape") { zoom(null); clearSearch(); }
+  });
+}
+function num(g, name) { return parseFloat(g.getAttribute("data-" + name)); }
+function details(text) { document.getElementById("details").textContent = text; }
+function fit(g, width) {
+  var label = g.getAttribute("data-l"), chars = Math.floor((width - 6) / CHAR_WIDTH);
+  var text = g.querySelector("text");
+  if (chars < 3) { text.textContent = ""; }
+  else if (label.length <= chars) { text.textContent = label; }
+  else { text.textContent = label.slice(0, chars - 2) + ".."; }
+}
+function zoom(target) {
+  var zx = 0, zw = 1, zd = 0, eps = 1e-9;
+  if (target) { zx = num(target, "x"); zw = num(target, "w"); zd = num(target, "d"); }
+  document.getElementById("reset").classList.toggle("hide", !target);
+  frames.forEach(function (g) {
+    var x = num(g, "x"), w = num(g, "w"), d = num(g, "d"), nx, nw;
+    if (d < zd && x <= zx + eps && x + w >= zx + zw - eps) {
+      nx = 0; nw = graphWidth; g.style.opacity = "0.5";
+    } else if (d >= zd && x >= zx - eps && x + w <= zx + zw + eps) {
+      nx = (x - zx) / zw * graphWidth; nw = w / zw * graphWidth; g.style.opacity = "";
+    } else {
+      g.style.display = "none"; return;
+    }
+    g.style.display = "";
+    var rect = g.querySelector("rect"), text = g.querySelector("text");
+    rect.setAttribute("x", PADDING + nx);
+    rect.setAttribute("width", nw);
+    text.setAttribute("x", PADDING + nx + 3);
+    fit(g, nw);
+  });
+}
+function search() {
+  var term = prompt("Search for (regular expression):", searchRe ? searchRe.source : "");
+  if (term === null) { return; }
+  if (term === "") { clearSearch(); return; }
+  try { searchRe = new RegExp(term); } catch (e) { alert(e.message); return; }
+  var widest = {};
+  frames.forEach(function (g) {
+    var rect = g.querySelector("rect");
+    if (searchRe.test(g.getAttribute("data-l")) || searchRe.test(g.getAttribute("data-s"))) {
+      rect.setAttribute("fill", "#e600e6");
+      var x = num(g, "x"), w = num(g, "w");
+      if (!(x in widest) || widest[x] < w) { widest[x] = w; }
+    } else {
+      rect.setAttribute("fill", g.getAttribute("data-c"));
+    }
+  });
+  var matched = 0, end = -1;
+  Object.keys(widest).map(Number).sort(function (a, b) { return a - b; }).forEach(function (x) {
+    if (x >= end - 1e-12) { matched += widest[x]; end = x + widest[x]; }
+  });
+  document.getElementById("matched").textContent = "Matched: " + (matched * 100).toFixed(2) + "%";
+  document.getElementById("search").textContent = "Clear search";
+}
+function clearSearch() {
+  searchRe = null;
+  frames.forEach(function (g) { g.querySelector("rect").setAttribute("fill", g.getAttribute("data-c")); });
+  document.getElementById("matched").textContent = " ";
+  document.getElementById("search").textContent = "Search";
+}
+"##;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::speedscope_format::{Extra, Frame, Profile, SampledProfile, Shared};
+
+    fn frame(name: &str, file: &str, line: u32) -> Frame {
+        Frame {
+            name: name.to_string(),
+            file: Some(file.to_string()),
+            line: Some(line),
+            ..Frame::default()
+        }
+    }
+
+    fn input() -> Speedscope {
+        let profile = |name: &str, samples: Vec<Vec<i32>>, weights: Vec<f64>| {
+            Profile::Sampled(SampledProfile {
+                name: name.to_string(),
+                unit: "milliseconds".to_string(),
+                end_value: weights.iter().sum(),
+                samples,
+                weights,
+                ..SampledProfile::default()
+            })
+        };
+        Speedscope {
+            profiles: vec![
+                profile(
+                    "MainThread",
+                    vec![vec![0, 1], vec![0, 2], vec![]],
+                    vec![10.0, 20.0, 5.0],
+                ),
+                profile("Worker", vec![vec![0, 1]], vec![10.0]),
+            ],
+            shared: Shared {
+                frames: vec![
+                    frame("main", "/app/main.py", 1),
+                    frame("query", "/app/db.py", 5),
+                    frame("<render>", "/app/views.py", 9),
+                ],
+                extra: Extra::new(),
+            },
+            schema: String::new(),
+            exporter: None,
+            name: None,
+            active_profile_index: None,
+            extra: Extra::new(),
+        }
+    }
+
+    #[test]
+    fn test_stacks_merge_across_profiles() {
+        let graph = FlameGraph::from_speedscope(&input(), false).unwrap();
+
+        assert_eq!(graph.unit, "seconds");
+        assert_eq!(graph.root.samples, 3);
+        assert!((graph.root.value - 0.04).abs() < 1e-12);
+        let main = graph.root.children.values().next().unwrap();
+        let children: Vec<(&str, usize)> = main
+            .children
+            .values()
+            .map(|child| (child.name.as_str(), child.samples))
+            .collect();
+        assert_eq!(children, vec![("<render>", 1), ("query", 2)]);
+    }
+
+    #[test]
+    fn test_thread_names_add_a_level() {
+        let graph = FlameGraph::from_speedscope(&input(), true).unwrap();
+
+        let threads: Vec<&str> = graph
+            .root
+            .children
+            .values()
+            .map(|thread| thread.name.as_str())
+            .collect();
+        assert_eq!(threads, vec!["MainThread", "Worker"]);
+        assert!(graph.root.children.values().all(|thread| thread.is_thread));
+    }
+
+    #[test]
+    fn test_mixed_units_that_are_not_time_are_rejected() {
+        let mut speedscope = input();
+        for (profile, unit) in speedscope.profiles.iter_mut().zip(["none", "bytes"]) {
+            if let Profile::Sampled(profile) = profile {
+                profile.unit = unit.to_string();
+            }
+        }
+
+        assert!(FlameGraph::from_speedscope(&speedscope, false).is_err());
+    }
+
+    #[test]
+    fn test_layout_places_children_side_by_side() {
+        let graph = FlameGraph::from_speedscope(&input(), false).unwrap();
+
+        let placed: Vec<(&str, f64, f64, usize)> = layout(&graph.root)
+            .iter()
+            .map(|f| (f.node.name.as_str(), f.x, f.width, f.depth))
+            .collect();
+
+        assert_eq!(
+            placed,
+            vec![
+                ("all", 0.0, 1.0, 0),
+                ("main", 0.0, 1.0, 1),
+                ("<render>", 0.0, 0.5, 2),
+                ("query", 0.5, 0.5, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_svg_is_self_contained_and_escaped() {
+        let mut out = Vec::new();
+        write_flamegraph(&input(), &FlamegraphOptions::default(), &mut out).unwrap();
+        let svg = String::from_utf8(out).unwrap();
+
+        assert!(svg.contains("&lt;render&gt; (/app/views.py:9): 50.00%, 0.020 s, 1 samples"));
+        assert!(svg.contains("<script"));
+        assert!(!svg.contains("<render>"));
+        assert!(!svg.contains("https://"));
+        assert!(!svg.contains("xlink:href"));
+    }
+
+    #[test]
+    fn test_icicle_puts_roots_at_the_top() {
+        let svg = |direction| {
+            let mut out = Vec::new();
+            let options = FlamegraphOptions {
+                direction,
+                ..FlamegraphOptions::default()
+            };
+            write_flamegraph(&input(), &options, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let root_y = |svg: &str| {
+            let all = svg.find(r#"data-l="all""#).unwrap();
+            let y = svg[all..].find(r#" y=""#).unwrap() + all + 4;
+            svg[y..y + svg[y..].find('"').unwrap()]
+                .parse::<f64>()
+                .unwrap()
+        };
+
+        assert_eq!(root_y(&svg(Direction::Down)), HEADER_HEIGHT);
+        assert_eq!(
+            root_y(&svg(Direction::Up)),
+            HEADER_HEIGHT + 2.0 * FRAME_HEIGHT
+        );
+    }
+
+    #[test]
+    fn test_module_colors_are_deterministic() {
+        let views = module_color(Some("/venv/lib/python3.11/site-packages/django/views.py"));
+
+        assert_eq!(
+            views,
+            module_color(Some("/other/site-packages/django/views.py"))
+        );
+        assert_ne!(views, module_color(Some("/app/db.py")));
+        assert_eq!(views.len(), 7);
+    }
+
+    #[test]
+    fn test_baseline_comes_from_matching_nodes() {
+        let mut new = FlameGraph::from_speedscope(&input(), false).unwrap();
+        let mut base_input = input();
+        base_input.profiles.truncate(1);
+        let base = FlameGraph::from_speedscope(&base_input, false).unwrap();
+
+        new.root.set_baseline(Some(&base.root));
+
+        let main = new.root.children.values().next().unwrap();
+        let query = main.children.values().find(|n| n.name == "query").unwrap();
+        assert!((query.value - 0.02).abs() < 1e-12);
+        assert!((query.baseline - 0.01).abs() < 1e-12);
+        assert!(diff_color(query).starts_with("#"));
+        assert_ne!(diff_color(query), diff_color(main));
+    }
+
+    #[test]
+    fn test_labels_are_shortened_to_fit() {
+        assert_eq!(fit_label("handle_request", 200.0), "handle_request");
+        assert_eq!(fit_label("handle_request", 62.0), "handle..");
+        assert_eq!(fit_label("handle_request", 20.0), "");
+    }
+}
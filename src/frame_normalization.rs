@@ -0,0 +1,217 @@
+use crate::speedscope_format::Frame;
+use regex::Regex;
+use std::borrow::Cow;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// Replaces `from` at the start of frame file paths with `to`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixRewrite {
+    pub from: String,
+    pub to: String,
+}
+
+impl FromStr for PrefixRewrite {
+    type Err = String;
+
+    /// Parses `FROM=TO`, an empty `TO` removes the prefix
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((from, to)) if !from.is_empty() => Ok(PrefixRewrite {
+                from: from.to_string(),
+                to: to.to_string(),
+            }),
+            _ => Err(format!("expected FROM=TO, got {:?}", s)),
+        }
+    }
+}
+
+/// Rules applied to frames before they are interned, so frames that only differ by where
+/// or from which build they were recorded are treated as the same frame
+#[derive(Debug, Clone, Default)]
+pub struct FrameNormalization {
+    /// Applied in order, only the first rewrite whose prefix matches is used
+    pub path_prefixes: Vec<PrefixRewrite>,
+    /// Drop line and column, which shift between versions of the same code
+    pub strip_lines: bool,
+    /// Replace the file path by the dotted Python module name derived from it
+    pub module_names: bool,
+}
+
+impl FrameNormalization {
+    fn is_identity(&self) -> bool {
+        self.path_prefixes.is_empty() && !self.strip_lines && !self.module_names
+    }
+
+    /// The frame with the rules applied, borrowed when there is nothing to apply
+    pub fn apply<'a>(&self, frame: &'a Frame) -> Cow<'a, Frame> {
+        if self.is_identity() {
+            return Cow::Borrowed(frame);
+        }
+        let mut frame = frame.clone();
+        if let Some(file) = &mut frame.file {
+            if let Some(rewrite) = self
+                .path_prefixes
+                .iter()
+                .find(|rewrite| file.starts_with(&rewrite.from))
+            {
+                file.replace_range(..rewrite.from.len(), &rewrite.to);
+            }
+            if self.module_names {
+                *file = module_name(file);
+            }
+        }
+        if self.strip_lines {
+            frame.line = None;
+            frame.col = None;
+        }
+        Cow::Owned(frame)
+    }
+}
+
+/// Dotted module name of a Python source file.
+///
+/// Installed packages are named from their `site-packages` or `dist-packages` directory and
+/// otherwise the standard library from its `lib/pythonX.Y` directory, so the same module gets the
+/// same name whichever interpreter or virtualenv it was loaded from. Other paths are used
+/// whole, so rewrite their prefix first to get names relative to the application root.
+pub fn module_name(file: &str) -> String {
+    let file = file.replace('\\', "/");
+    let mut relative = file.as_str();
+    let mut installed = false;
+    for marker in ["/site-packages/", "/dist-packages/"] {
+        if let Some(index) = relative.rfind(marker) {
+            relative = &relative[index + marker.len()..];
+            installed = true;
+        }
+    }
+    if !installed {
+        static STDLIB: OnceLock<Regex> = OnceLock::new();
+        let stdlib = STDLIB.get_or_init(|| Regex::new(r"(^|/)lib/python\d+(\.\d+)?/").unwrap());
+        if let Some(stdlib_dir) = stdlib.find_iter(relative).last() {
+            relative = &relative[stdlib_dir.end()..];
+        }
+    }
+    let relative = relative.strip_suffix(".py").unwrap_or(relative);
+    let relative = relative.strip_suffix("/__init__").unwrap_or(relative);
+    relative.trim_start_matches('/').replace('/', ".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(file: &str, line: u32) -> Frame {
+        Frame {
+            name: "handle".to_string(),
+            file: Some(file.to_string()),
+            line: Some(line),
+            col: Some(4),
+            ..Frame::default()
+        }
+    }
+
+    #[test]
+    fn test_default_keeps_frame_borrowed() {
+        let frame = frame("/app/views.py", 3);
+
+        assert!(matches!(
+            FrameNormalization::default().apply(&frame),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn test_first_matching_prefix_is_rewritten() {
+        let normalization = FrameNormalization {
+            path_prefixes: vec![
+                "/usr/local/lib/python3.10/site-packages/=".parse().unwrap(),
+                "/opt/venv/lib/python3.11/site-packages/=".parse().unwrap(),
+                "/opt/=/srv/".parse().unwrap(),
+            ],
+            ..FrameNormalization::default()
+        };
+
+        let system = normalization
+            .apply(&frame(
+                "/usr/local/lib/python3.10/site-packages/django/core/handlers/base.py",
+                181,
+            ))
+            .into_owned();
+        let venv = normalization
+            .apply(&frame(
+                "/opt/venv/lib/python3.11/site-packages/django/core/handlers/base.py",
+                181,
+            ))
+            .into_owned();
+
+        assert_eq!(system.file.as_deref(), Some("django/core/handlers/base.py"));
+        assert_eq!(system.key(), venv.key());
+    }
+
+    #[test]
+    fn test_strip_lines_merges_shifted_frames() {
+        let normalization = FrameNormalization {
+            strip_lines: true,
+            ..FrameNormalization::default()
+        };
+
+        let old = normalization
+            .apply(&frame("/app/views.py", 10))
+            .into_owned();
+        let new = normalization
+            .apply(&frame("/app/views.py", 12))
+            .into_owned();
+
+        assert_eq!(old.line, None);
+        assert_eq!(old.col, None);
+        assert_eq!(old.key(), new.key());
+    }
+
+    #[test]
+    fn test_module_names_from_file_paths() {
+        assert_eq!(
+            module_name("/usr/local/lib/python3.10/site-packages/django/core/handlers/base.py"),
+            "django.core.handlers.base"
+        );
+        assert_eq!(
+            module_name("/opt/venv/lib/python3.11/site-packages/requests/__init__.py"),
+            "requests"
+        );
+        assert_eq!(
+            module_name("/usr/lib/python3/dist-packages/yaml/loader.py"),
+            "yaml.loader"
+        );
+        assert_eq!(
+            module_name("/usr/local/lib/python3.10/concurrent/futures/thread.py"),
+            "concurrent.futures.thread"
+        );
+        assert_eq!(module_name("myapp/views.py"), "myapp.views");
+    }
+
+    #[test]
+    fn test_stdlib_rule_only_applies_outside_installed_packages() {
+        assert_eq!(
+            module_name("/opt/venv/lib/python3.11/site-packages/mylib/lib/python_utils/x.py"),
+            "mylib.lib.python_utils.x"
+        );
+        assert_eq!(
+            module_name("/srv/app/lib/python_utils/x.py"),
+            "srv.app.lib.python_utils.x"
+        );
+        assert_eq!(module_name("lib/python3/json/decoder.py"), "json.decoder");
+    }
+
+    #[test]
+    fn test_prefix_rewrite_requires_separator() {
+        assert!("/app/src".parse::<PrefixRewrite>().is_err());
+        assert!("=/srv".parse::<PrefixRewrite>().is_err());
+        assert_eq!(
+            "/app/src/=".parse::<PrefixRewrite>().unwrap(),
+            PrefixRewrite {
+                from: "/app/src/".to_string(),
+                to: String::new(),
+            }
+        );
+    }
+}
@@ -0,0 +1,295 @@
+use crate::error::{Error, Result};
+use crate::file_io::{self, Compression};
+use std::collections::HashSet;
+use std::io::{self, BufRead};
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// Stands for stdin wherever a path list is expected
+pub const STDIN: &str = "-";
+
+/// Where the input files of a combine come from
+#[derive(Debug, Default, Clone)]
+pub struct InputSources {
+    /// Files listing one input per line, `-` reads the list from stdin
+    pub list_files: Vec<String>,
+    /// Files, globs or directories given on the command line, `-` reads a list from stdin
+    pub paths: Vec<String>,
+    /// Extensions of the files picked up from directories, also matched
+    /// with a compression suffix such as `.gz` or `.zst`
+    pub extensions: Vec<String>,
+    /// Output of the command, never picked up from a directory or glob so a previous
+    /// result isn't read back in
+    pub exclude: Option<String>,
+}
+
+impl InputSources {
+    /// Expands all sources into input file paths, in the order they are given.
+    ///
+    /// Every entry, whether given directly or read from a list, may be a file, a glob or a
+    /// directory that is searched recursively. Glob and directory matches are sorted,
+    /// and a file reached more than once is only combined once. `exclude` is left out of
+    /// glob and directory matches, but kept when given by name.
+    pub fn discover(&self, stdin: impl BufRead) -> Result<Vec<String>> {
+        let mut stdin = Some(stdin);
+        let mut entries = Vec::new();
+        for list_file in &self.list_files {
+            entries.extend(read_list(list_file, &mut stdin)?);
+        }
+        for path in &self.paths {
+            if path == STDIN {
+                entries.extend(read_list(STDIN, &mut stdin)?);
+            } else {
+                entries.push(path.clone());
+            }
+        }
+
+        // only an output that already exists can be matched
+        let excluded = self
+            .exclude
+            .as_deref()
+            .filter(|output| *output != file_io::STDOUT)
+            .and_then(|output| std::fs::canonicalize(output).ok());
+        let mut seen = HashSet::new();
+        let mut inputs = Vec::new();
+        for entry in entries {
+            for input in self.expand(&entry, excluded.as_deref())? {
+                if seen.insert(input.clone()) {
+                    inputs.push(input);
+                }
+            }
+        }
+        if inputs.is_empty() {
+            let sources: Vec<&str> = self
+                .list_files
+                .iter()
+                .chain(&self.paths)
+                .map(String::as_str)
+                .collect();
+            return Err(Error::io(
+                sources.join(", "),
+                io::Error::new(io::ErrorKind::NotFound, "no input files found"),
+            ));
+        }
+        Ok(inputs)
+    }
+
+    fn expand(&self, entry: &str, excluded: Option<&Path>) -> Result<Vec<String>> {
+        let is_excluded = |path: &Path| {
+            excluded
+                .is_some_and(|excluded| std::fs::canonicalize(path).is_ok_and(|p| p == excluded))
+        };
+        if Path::new(entry).is_dir() {
+            return self.walk(entry, is_excluded);
+        }
+        if !entry.contains(['*', '?', '[']) {
+            return Ok(vec![entry.to_string()]);
+        }
+        let invalid_glob = |reason: String| Error::io(entry, io::Error::other(reason));
+        let mut matches = Vec::new();
+        for path in glob::glob(entry).map_err(|e| invalid_glob(e.to_string()))? {
+            let path = path.map_err(|e| invalid_glob(e.to_string()))?;
+            if path.is_file() && !is_excluded(&path) {
+                matches.push(path.display().to_string());
+            }
+        }
+        if matches.is_empty() {
+            return Err(Error::io(
+                entry,
+                io::Error::new(io::ErrorKind::NotFound, "no files match the pattern"),
+            ));
+        }
+        Ok(matches)
+    }
+
+    fn walk(&self, dir: &str, is_excluded: impl Fn(&Path) -> bool) -> Result<Vec<String>> {
+        let mut files = Vec::new();
+        for entry in WalkDir::new(dir).sort_by_file_name() {
+            let entry = entry.map_err(|e| Error::io(dir, e.into()))?;
+            let file_name = entry.file_name().to_string_lossy();
+            // compressed files count by the extension before the compression's
+            let uncompressed_name = Compression::strip_extension(&file_name);
+            let has_extension = self
+                .extensions
+                .iter()
+                .any(|extension| uncompressed_name.ends_with(&format!(".{}", extension)));
+            if entry.file_type().is_file() && has_extension && !is_excluded(entry.path()) {
+                files.push(entry.path().display().to_string());
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// Reads a path list, skipping blank lines and `#` comments.
+/// Stdin can only be read once, so a second `-` is an error.
+fn read_list(list_file: &str, stdin: &mut Option<impl BufRead>) -> Result<Vec<String>> {
+    let content = if list_file == STDIN {
+        let mut content = String::new();
+        let mut reader = stdin.take().ok_or_else(|| {
+            Error::io(
+                STDIN,
+                io::Error::new(io::ErrorKind::InvalidInput, "stdin can only be read once"),
+            )
+        })?;
+        reader
+            .read_to_string(&mut content)
+            .map_err(|e| Error::io(STDIN, e))?;
+        content
+    } else {
+        std::fs::read_to_string(list_file).map_err(|e| Error::io(list_file, e))?
+    };
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn touch(dir: &Path, name: &str) -> String {
+        let path = dir.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "{}").unwrap();
+        path.display().to_string()
+    }
+
+    fn sources(paths: Vec<String>) -> InputSources {
+        InputSources {
+            paths,
+            extensions: vec!["json".to_string()],
+            ..InputSources::default()
+        }
+    }
+
+    #[test]
+    fn test_list_file_skips_blank_lines_and_comments() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let first = touch(temp_dir.path(), "a.json");
+        let second = touch(temp_dir.path(), "b.json");
+        let list = temp_dir.path().join("all_profiles.txt");
+        std::fs::write(
+            &list,
+            format!(
+                "# chunks of the first run\n{}\n\n   \n  {}  \n",
+                first, second
+            ),
+        )
+        .unwrap();
+
+        let inputs = InputSources {
+            list_files: vec![list.display().to_string()],
+            ..InputSources::default()
+        }
+        .discover(io::empty())
+        .unwrap();
+
+        assert_eq!(inputs, vec![first, second]);
+    }
+
+    #[test]
+    fn test_directory_is_searched_recursively_by_extension() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let nested = touch(temp_dir.path(), "run_2/pyspy_output_2.json");
+        let top = touch(temp_dir.path(), "pyspy_output_1.json");
+        let compressed = touch(temp_dir.path(), "pyspy_output_3.json.gz");
+        let zstd = touch(temp_dir.path(), "pyspy_output_4.json.zstd");
+        touch(temp_dir.path(), "manifest.jsonl");
+        touch(temp_dir.path(), "notes.txt");
+
+        let inputs = sources(vec![temp_dir.path().display().to_string()])
+            .discover(io::empty())
+            .unwrap();
+
+        assert_eq!(inputs, vec![top, compressed, zstd, nested]);
+    }
+
+    #[test]
+    fn test_output_is_not_picked_up_from_directories_and_globs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let chunk = touch(temp_dir.path(), "pyspy_output_1.json");
+        let output = touch(temp_dir.path(), "combined_speedscope.json");
+        let with_output = |paths: Vec<String>| InputSources {
+            exclude: Some(output.clone()),
+            ..sources(paths)
+        };
+
+        let from_dir = with_output(vec![temp_dir.path().display().to_string()])
+            .discover(io::empty())
+            .unwrap();
+        let from_glob = with_output(vec![format!("{}/*.json", temp_dir.path().display())])
+            .discover(io::empty())
+            .unwrap();
+        let by_name = with_output(vec![output.clone()])
+            .discover(io::empty())
+            .unwrap();
+
+        assert_eq!(from_dir, vec![chunk.clone()]);
+        assert_eq!(from_glob, vec![chunk]);
+        assert_eq!(by_name, vec![output]);
+    }
+
+    #[test]
+    fn test_glob_matches_are_sorted() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let second = touch(temp_dir.path(), "pyspy_output_2.json");
+        let first = touch(temp_dir.path(), "pyspy_output_1.json");
+        touch(temp_dir.path(), "combined.txt");
+
+        let inputs = sources(vec![format!("{}/*.json", temp_dir.path().display())])
+            .discover(io::empty())
+            .unwrap();
+
+        assert_eq!(inputs, vec![first, second]);
+    }
+
+    #[test]
+    fn test_glob_without_matches_fails() {
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        let result =
+            sources(vec![format!("{}/*.json", temp_dir.path().display())]).discover(io::empty());
+
+        assert!(matches!(result, Err(Error::Io { .. })));
+    }
+
+    #[test]
+    fn test_stdin_list_and_duplicates() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let first = touch(temp_dir.path(), "a.json");
+        let second = touch(temp_dir.path(), "b.json");
+        let stdin = format!("{}\n# skipped\n{}\n", first, second);
+
+        let inputs = sources(vec![first.clone(), "-".to_string()])
+            .discover(stdin.as_bytes())
+            .unwrap();
+
+        assert_eq!(inputs, vec![first, second]);
+    }
+
+    #[test]
+    fn test_empty_directory_fails() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        touch(temp_dir.path(), "notes.txt");
+
+        let result = sources(vec![temp_dir.path().display().to_string()]).discover(io::empty());
+
+        assert!(matches!(result, Err(Error::Io { .. })));
+    }
+
+    #[test]
+    fn test_stdin_can_only_be_read_once() {
+        let result = InputSources {
+            list_files: vec!["-".to_string()],
+            paths: vec!["-".to_string()],
+            ..InputSources::default()
+        }
+        .discover(io::empty());
+
+        assert!(matches!(result, Err(Error::Io { path, .. }) if path == "-"));
+    }
+}
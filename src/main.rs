@@ -1,8 +1,100 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use collapsed_format::{CollapsedImportOptions, CollapsedOptions, CollapsedValues};
 use colored::*;
+use combine_speedscope::{CombineConfig, ImportOptions, ProfileOrder, TimeUnit, TimelineConfig};
+use diff_speedscope::{DiffConfig, DiffInput};
+use event_log::{Event, EventLog, LogFormat};
+use export::{ExportFormat, ExportOptions};
+use file_io::Compression;
+use flamegraph::{Direction, FlamegraphOptions};
+use frame_normalization::{FrameNormalization, PrefixRewrite};
+use input_discovery::{InputSources, STDIN};
+use pprof_format::{PprofImportOptions, PprofSplit};
+use profile_grouping::{GroupBy, Grouping};
+use regex::Regex;
+use report::{ReportConfig, ReportFormat, ReportGroup, ReportSort};
+use std::process::ExitCode;
+mod chunk_manifest;
+mod collapsed_format;
 mod combine_speedscope;
+mod diff_speedscope;
+mod error;
+mod event_log;
+mod export;
+mod file_io;
+mod firefox_format;
+mod flamegraph;
+mod frame_normalization;
+mod input_discovery;
+mod pprof_format;
+mod profile_grouping;
+mod report;
 mod run_continuos_pyspy;
 mod speedscope_format;
+mod trace_event_format;
+
+/// How inputs in other formats than speedscope are read
+#[derive(Args)]
+struct ImportArgs {
+    /// Samples per second collapsed-stack inputs were recorded at (py-spy records 100 by
+    /// default), turns their sample counts into seconds so they merge with speedscope inputs
+    #[arg(long)]
+    sample_rate: Option<f64>,
+
+    /// The first frame of every collapsed stack is the thread name
+    #[arg(long)]
+    collapsed_thread_names: bool,
+
+    /// How the samples of pprof inputs are split into profiles
+    #[arg(long, value_enum, default_value_t = PprofSplit::Label)]
+    pprof_split: PprofSplit,
+
+    /// Label splitting pprof samples into profiles with `--pprof-split label`
+    #[arg(long, default_value = pprof_format::THREAD_LABEL)]
+    pprof_label: String,
+}
+
+impl From<ImportArgs> for ImportOptions {
+    fn from(args: ImportArgs) -> Self {
+        ImportOptions {
+            collapsed: CollapsedImportOptions {
+                sample_rate: args.sample_rate,
+                thread_names: args.collapsed_thread_names,
+            },
+            pprof: PprofImportOptions {
+                split: args.pprof_split,
+                label: args.pprof_label,
+            },
+        }
+    }
+}
+
+/// Rules applied to frames before they are interned
+#[derive(Args)]
+struct NormalizationArgs {
+    /// Rewrite the start of frame file paths, as FROM=TO; can be given multiple times,
+    /// the first matching one is used
+    #[arg(long, value_name = "FROM=TO")]
+    rewrite_prefix: Vec<PrefixRewrite>,
+
+    /// Ignore line and column numbers of frames, so frames merge across code versions
+    #[arg(long)]
+    strip_lines: bool,
+
+    /// Replace frame file paths by the Python module name derived from them
+    #[arg(long)]
+    module_names: bool,
+}
+
+impl From<NormalizationArgs> for FrameNormalization {
+    fn from(args: NormalizationArgs) -> Self {
+        FrameNormalization {
+            path_prefixes: args.rewrite_prefix,
+            strip_lines: args.strip_lines,
+            module_names: args.module_names,
+        }
+    }
+}
 
 #[derive(Subcommand)]
 enum Commands {
@@ -22,11 +114,214 @@ enum Commands {
         /// Num of samples to take
         #[arg(short = 's', long)]
         num_of_samples: u16,
+
+        /// Compress each chunk after copying it, combine-speedscope-files reads them as is
+        #[arg(long, value_enum, default_value_t = Compression::None)]
+        compress: Compression,
     },
     CombineSpeedscopeFiles {
-        /// The file that contains paths to all of the relevant speedscope files
+        /// Speedscope files, globs (`profiling_results/*.json`) or directories to combine,
+        /// `-` reads a list of them from stdin
+        #[arg(required_unless_present = "all_profiles_file_path")]
+        inputs: Vec<String>,
+
+        /// The file that contains paths to all of the relevant speedscope files, one per line,
+        /// `-` reads it from stdin. Blank lines and lines starting with `#` are skipped
+        #[arg(short, long)]
+        all_profiles_file_path: Option<String>,
+
+        /// Extensions of the files picked up from directories
+        #[arg(long = "extension", default_value = "json")]
+        extensions: Vec<String>,
+
+        /// Where to write the combined file, `-` for stdout. Ending it with `.gz` or `.zst`
+        /// compresses it. Missing directories are created
+        #[arg(
+            short,
+            long,
+            default_value = "./profiling_results/combined_speedscope.json"
+        )]
+        output: String,
+
+        /// Overwrite the output if it already exists
+        #[arg(long)]
+        force: bool,
+
+        /// Number of threads used to parse and remap the input files, defaults to the number of cores
+        #[arg(short, long)]
+        jobs: Option<usize>,
+
+        /// Order of the profiles in the combined file
+        #[arg(long, value_enum, default_value_t = ProfileOrder::FirstSeen)]
+        profile_order: ProfileOrder,
+
+        /// Combine only the inputs that are valid and list the skipped ones, instead of failing
+        #[arg(long)]
+        skip_invalid: bool,
+
+        /// Combine chunks in the order they were recorded, spanning the recorded session.
+        /// Samples stay back to back unless --insert-gaps is given
+        #[arg(long)]
+        timeline: bool,
+
+        /// Manifest with the start time of each chunk, written by run-continuos-pyspy.
+        /// Chunks missing from it are placed by their file modification time
+        #[arg(long, requires = "timeline")]
+        manifest: Option<String>,
+
+        /// Fill the time between chunks with empty samples, so the time axis matches the wall clock
+        #[arg(long, requires = "timeline")]
+        insert_gaps: bool,
+
+        /// Convert all profiles to this unit, by default each profile keeps the unit of its first chunk
+        #[arg(long, value_enum)]
+        unit: Option<TimeUnit>,
+
+        /// How profiles of different inputs are matched up to be merged
+        #[arg(long, value_enum, default_value_t = Grouping::Exact)]
+        group_by: Grouping,
+
+        /// Regex used by `--group-by regex`, profiles are merged by its first capture group
+        #[arg(long, required_if_eq("group_by", "regex"))]
+        group_regex: Option<Regex>,
+
+        #[command(flatten)]
+        normalization: NormalizationArgs,
+
+        #[command(flatten)]
+        import: ImportArgs,
+    },
+    /// Converts a profile, such as a combined speedscope file, to another profile format
+    Export {
+        /// The speedscope, pprof or collapsed-stack file to convert
+        #[arg(short, long)]
+        input: String,
+
+        /// Format to convert to
+        #[arg(short, long, value_enum)]
+        format: ExportFormat,
+
+        /// Where to write the result, `-` for stdout. Ending it with `.gz` or `.zst`
+        /// compresses it. Missing directories are created
+        #[arg(short, long)]
+        output: String,
+
+        /// Overwrite the output if it already exists
+        #[arg(long)]
+        force: bool,
+
+        /// collapsed, flamegraph, icicle: start every stack with the thread (profile) name
+        #[arg(long)]
+        thread_names: bool,
+
+        /// flamegraph, icicle: title above the graph, the profile name by default
+        #[arg(long)]
+        title: Option<String>,
+
+        /// collapsed: include line numbers in frames
+        #[arg(long)]
+        line_numbers: bool,
+
+        /// collapsed: whether stacks are valued by sample weights or sample counts
+        #[arg(long, value_enum, default_value_t = CollapsedValues::Weights)]
+        values: CollapsedValues,
+
+        #[command(flatten)]
+        import: ImportArgs,
+    },
+    /// Compares two profiles or sessions: writes a differential flamegraph and prints the
+    /// functions and stacks that grew or shrank the most
+    DiffSpeedscope {
+        /// Profile or session before the change: a file, glob or directory of chunks, or `-`
+        /// for a list of them on stdin, which only one side can read
+        base: String,
+
+        /// Profile or session after the change: a file, glob or directory of chunks, or `-`
+        /// for a list of them on stdin, which only one side can read
+        new: String,
+
+        /// Extensions of the files picked up from directories
+        #[arg(long = "extension", default_value = "json")]
+        extensions: Vec<String>,
+
+        /// Where to write the differential flamegraph SVG, `-` for stdout. Missing
+        /// directories are created
+        #[arg(short, long, default_value = "./profiling_results/diff_flamegraph.svg")]
+        output: String,
+
+        /// Overwrite the output if it already exists
+        #[arg(long)]
+        force: bool,
+
+        /// Number of regressions, improvements and stacks listed in the table
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+
+        /// Title above the flamegraph, "<new> vs <base>" by default
+        #[arg(long)]
+        title: Option<String>,
+
+        /// Number of threads used to parse and remap the input files, defaults to the number of cores
         #[arg(short, long)]
-        all_profiles_file_path: String,
+        jobs: Option<usize>,
+
+        /// Compare only the inputs that are valid and list the skipped ones, instead of failing
+        #[arg(long)]
+        skip_invalid: bool,
+
+        #[command(flatten)]
+        normalization: NormalizationArgs,
+
+        #[command(flatten)]
+        import: ImportArgs,
+    },
+    /// Summarizes the hottest functions, files or modules of profiles in the terminal
+    Report {
+        /// Speedscope files, globs or directories to summarize, `-` reads a list of them from stdin
+        #[arg(required = true)]
+        inputs: Vec<String>,
+
+        /// Extensions of the files picked up from directories
+        #[arg(long = "extension", default_value = "json")]
+        extensions: Vec<String>,
+
+        /// Where to write the report, stdout by default
+        #[arg(short, long, default_value = file_io::STDOUT)]
+        output: String,
+
+        /// Overwrite the output if it already exists
+        #[arg(long)]
+        force: bool,
+
+        /// Format of the report
+        #[arg(short, long, value_enum, default_value_t = ReportFormat::Text)]
+        format: ReportFormat,
+
+        /// What the rows of the report are: functions, source files or Python modules
+        #[arg(long, value_enum, default_value_t = ReportGroup::Function)]
+        by: ReportGroup,
+
+        /// Rank rows by their self or their inclusive weight
+        #[arg(long, value_enum, default_value_t = ReportSort::SelfWeight)]
+        sort: ReportSort,
+
+        /// Number of rows to show, 0 shows all of them
+        #[arg(long, default_value_t = 20)]
+        top: usize,
+
+        /// Number of threads used to parse and remap the input files, defaults to the number of cores
+        #[arg(short, long)]
+        jobs: Option<usize>,
+
+        /// Summarize only the inputs that are valid and list the skipped ones, instead of failing
+        #[arg(long)]
+        skip_invalid: bool,
+
+        #[command(flatten)]
+        normalization: NormalizationArgs,
+
+        #[command(flatten)]
+        import: ImportArgs,
     },
 }
 
@@ -37,74 +332,310 @@ enum Commands {
     about = "useful utils for pyspy"
 )]
 struct Cli {
+    /// Format of the progress output, `json` emits one event per line for automation
+    #[arg(long, value_enum, global = true, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
     #[command(subcommand)]
     command: Commands,
 }
 
-fn main() {
+fn main() -> ExitCode {
     let cli = Cli::parse();
+    let log = EventLog::new(cli.log_format);
 
-    match cli.command {
+    let result = match cli.command {
         Commands::RunContinuosPyspy {
             pod_name,
             namespace,
             duration_seconds,
             num_of_samples,
+            compress,
         } => {
-            println!(
-                "{}",
-                format!(
-                    "====> Running continuos pyspy profiling on pod {} in namespace {} for {} seconds",
-                    pod_name, namespace, duration_seconds
-                )
-                .green()
-            );
-            match run_continuos_pyspy::run_continuos_pyspy(
+            let result = run_continuos_pyspy::run_continuos_pyspy(
                 pod_name,
                 namespace,
                 duration_seconds,
                 num_of_samples,
-            ) {
-                Ok(_) => println!(
-                    "{}",
-                    format!("====> Successfuly finished running pyspy profiling").green()
+                compress,
+                &log,
+            );
+            match &result {
+                Ok(_) => log.info(
+                    &"====> Successfuly finished running pyspy profiling"
+                        .green()
+                        .to_string(),
                 ),
-                Err(e) => eprintln!(
+                // chunk and install failures were already reported by their event
+                Err(e) if !log.reported_failure() => eprintln!(
                     "{}",
                     format!("====> Error running continuos pyspy profiling: {}", e).red()
                 ),
+                Err(_) => {}
             }
+            result
         }
         Commands::CombineSpeedscopeFiles {
+            inputs,
             all_profiles_file_path,
+            extensions,
+            output,
+            force,
+            jobs,
+            profile_order,
+            skip_invalid,
+            timeline,
+            manifest,
+            insert_gaps,
+            unit,
+            group_by,
+            group_regex,
+            normalization,
+            import,
         } => {
-            println!(
-                "{}",
-                format!(
-                    "====> Combining speedscope files from {}",
-                    all_profiles_file_path
-                )
-                .green()
-            );
-            let combined_speedscope_file_path = "./profiling_results/combined_speedscope.json";
-            match combine_speedscope::entry_point(
-                &all_profiles_file_path,
-                &combined_speedscope_file_path,
-            ) {
-                Ok(_) => println!(
-                    "{}",
-                    format!(
-                        "====> Successfuly combined speedscope files to {}",
-                        combined_speedscope_file_path
+            // keep stdout clean for the combined file
+            let log = if output == file_io::STDOUT {
+                log.on_stderr()
+            } else {
+                log
+            };
+            let sources = InputSources {
+                list_files: all_profiles_file_path.into_iter().collect(),
+                paths: inputs,
+                extensions,
+                exclude: Some(output.clone()),
+            };
+            let config = CombineConfig {
+                jobs,
+                profile_order,
+                skip_invalid,
+                timeline: timeline.then_some(TimelineConfig {
+                    manifest_path: manifest,
+                    insert_gaps,
+                }),
+                unit,
+                group_by: GroupBy::new(group_by, group_regex)
+                    .expect("clap requires --group-regex for --group-by regex"),
+                normalization: normalization.into(),
+                force,
+                import: import.into(),
+            };
+            let result = sources
+                .discover(std::io::stdin().lock())
+                .and_then(|input_paths| {
+                    log.info(
+                        &format!("====> Combining {} speedscope files", input_paths.len())
+                            .green()
+                            .to_string(),
+                    );
+                    combine_speedscope::entry_point(&input_paths, &output, &config)
+                });
+            if let Ok(summary) = &result {
+                for skipped in &summary.skipped {
+                    log.emit(Event::InputSkipped {
+                        error: skipped.to_string(),
+                    });
+                }
+            }
+            log.emit(Event::CombineFinished {
+                inputs: result.as_ref().map_or(0, |summary| summary.num_of_inputs),
+                skipped: result.as_ref().map_or(0, |summary| summary.skipped.len()),
+                output,
+                success: result.is_ok(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+            });
+            result.map(|_| ())
+        }
+
+        Commands::Export {
+            input,
+            format,
+            output,
+            force,
+            thread_names,
+            title,
+            line_numbers,
+            values,
+            import,
+        } => {
+            let log = if output == file_io::STDOUT {
+                log.on_stderr()
+            } else {
+                log
+            };
+            let options = ExportOptions {
+                import: import.into(),
+                collapsed: CollapsedOptions {
+                    thread_names,
+                    line_numbers,
+                    values,
+                },
+                flamegraph: FlamegraphOptions {
+                    direction: match format {
+                        ExportFormat::Icicle => Direction::Down,
+                        _ => Direction::Up,
+                    },
+                    title,
+                    thread_names,
+                },
+            };
+            let result = export::entry_point(&input, &output, format, &options, force);
+            log.emit(Event::ExportFinished {
+                input,
+                output,
+                format: format
+                    .to_possible_value()
+                    .expect("export formats are never skipped")
+                    .get_name()
+                    .to_string(),
+                success: result.is_ok(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+            });
+            result
+        }
+        Commands::DiffSpeedscope {
+            base,
+            new,
+            extensions,
+            output,
+            force,
+            top,
+            title,
+            jobs,
+            skip_invalid,
+            normalization,
+            import,
+        } => {
+            if base == STDIN && new == STDIN {
+                Cli::command()
+                    .error(
+                        clap::error::ErrorKind::ArgumentConflict,
+                        "<BASE> and <NEW> can't both be `-`, stdin can only be read once",
+                    )
+                    .exit();
+            }
+            // stdout carries the table, or the graph when it is written there
+            let log = log.on_stderr();
+            let discover = |spec: &String| {
+                InputSources {
+                    list_files: Vec::new(),
+                    paths: vec![spec.clone()],
+                    extensions: extensions.clone(),
+                    exclude: Some(output.clone()),
+                }
+                .discover(std::io::stdin().lock())
+                .map(|paths| DiffInput {
+                    name: spec.clone(),
+                    paths,
+                })
+            };
+            let config = DiffConfig {
+                jobs,
+                skip_invalid,
+                normalization: normalization.into(),
+                import: import.into(),
+                title,
+                force,
+            };
+            let result = discover(&base).and_then(|base_input| {
+                let new_input = discover(&new)?;
+                log.info(
+                    &format!(
+                        "====> Comparing {} files of {} with {} files of {}",
+                        new_input.paths.len(),
+                        new,
+                        base_input.paths.len(),
+                        base
                     )
                     .green()
-                ),
-
-                Err(e) => eprintln!(
-                    "{}",
-                    format!("====> Error combining speedscope files: {}", e).red()
-                ),
+                    .to_string(),
+                );
+                diff_speedscope::entry_point(&base_input, &new_input, &output, &config)
+            });
+            if let Ok(report) = &result {
+                for skipped in &report.skipped {
+                    log.emit(Event::InputSkipped {
+                        error: skipped.to_string(),
+                    });
+                }
+                let table = diff_speedscope::format_table(report, top);
+                if output == file_io::STDOUT {
+                    eprint!("{}", table);
+                } else {
+                    print!("{}", table);
+                }
             }
+            log.emit(Event::DiffFinished {
+                base,
+                new,
+                output,
+                regressions: result.as_ref().map_or(0, |report| report.regressions.len()),
+                improvements: result
+                    .as_ref()
+                    .map_or(0, |report| report.improvements.len()),
+                success: result.is_ok(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+            });
+            result.map(|_| ())
         }
+        Commands::Report {
+            inputs,
+            extensions,
+            output,
+            force,
+            format,
+            by,
+            sort,
+            top,
+            jobs,
+            skip_invalid,
+            normalization,
+            import,
+        } => {
+            let log = if output == file_io::STDOUT {
+                log.on_stderr()
+            } else {
+                log
+            };
+            let sources = InputSources {
+                list_files: Vec::new(),
+                paths: inputs,
+                extensions,
+                exclude: Some(output.clone()),
+            };
+            let config = ReportConfig {
+                group: by,
+                sort,
+                top,
+                format,
+                jobs,
+                skip_invalid,
+                normalization: normalization.into(),
+                import: import.into(),
+                force,
+            };
+            let result = sources
+                .discover(std::io::stdin().lock())
+                .and_then(|input_paths| report::entry_point(&input_paths, &output, &config));
+            if let Ok(summary) = &result {
+                for skipped in &summary.skipped {
+                    log.emit(Event::InputSkipped {
+                        error: skipped.to_string(),
+                    });
+                }
+            }
+            log.emit(Event::ReportFinished {
+                inputs: result.as_ref().map_or(0, |summary| summary.num_of_inputs),
+                output,
+                success: result.is_ok(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+            });
+            result.map(|_| ())
+        }
+    };
+
+    match result {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => ExitCode::from(e.exit_code()),
     }
 }
@@ -0,0 +1,816 @@
+use crate::combine_speedscope::SCHEMA;
+use crate::speedscope_format::{
+    seconds_per_unit, Extra, Frame, FrameKey, Profile, SampledProfile, Shared, Speedscope,
+};
+use prost::Message;
+use std::collections::HashMap;
+
+/// Messages of pprof's profile.proto (github.com/google/pprof/blob/main/proto/profile.proto)
+pub mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Profile {
+        #[prost(message, repeated, tag = "1")]
+        pub sample_type: Vec<ValueType>,
+        #[prost(message, repeated, tag = "2")]
+        pub sample: Vec<Sample>,
+        #[prost(message, repeated, tag = "3")]
+        pub mapping: Vec<Mapping>,
+        #[prost(message, repeated, tag = "4")]
+        pub location: Vec<Location>,
+        #[prost(message, repeated, tag = "5")]
+        pub function: Vec<Function>,
+        /// Index 0 is always the empty string
+        #[prost(string, repeated, tag = "6")]
+        pub string_table: Vec<String>,
+        #[prost(int64, tag = "7")]
+        pub drop_frames: i64,
+        #[prost(int64, tag = "8")]
+        pub keep_frames: i64,
+        #[prost(int64, tag = "9")]
+        pub time_nanos: i64,
+        #[prost(int64, tag = "10")]
+        pub duration_nanos: i64,
+        #[prost(message, optional, tag = "11")]
+        pub period_type: Option<ValueType>,
+        #[prost(int64, tag = "12")]
+        pub period: i64,
+        #[prost(int64, repeated, tag = "13")]
+        pub comment: Vec<i64>,
+        #[prost(int64, tag = "14")]
+        pub default_sample_type: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ValueType {
+        #[prost(int64, tag = "1")]
+        pub r#type: i64,
+        #[prost(int64, tag = "2")]
+        pub unit: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Sample {
+        /// Leaf first
+        #[prost(uint64, repeated, tag = "1")]
+        pub location_id: Vec<u64>,
+        /// One per sample type
+        #[prost(int64, repeated, tag = "2")]
+        pub value: Vec<i64>,
+        #[prost(message, repeated, tag = "3")]
+        pub label: Vec<Label>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Label {
+        #[prost(int64, tag = "1")]
+        pub key: i64,
+        #[prost(int64, tag = "2")]
+        pub str: i64,
+        #[prost(int64, tag = "3")]
+        pub num: i64,
+        #[prost(int64, tag = "4")]
+        pub num_unit: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Mapping {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(uint64, tag = "2")]
+        pub memory_start: u64,
+        #[prost(uint64, tag = "3")]
+        pub memory_limit: u64,
+        #[prost(uint64, tag = "4")]
+        pub file_offset: u64,
+        #[prost(int64, tag = "5")]
+        pub filename: i64,
+        #[prost(int64, tag = "6")]
+        pub build_id: i64,
+        #[prost(bool, tag = "7")]
+        pub has_functions: bool,
+        #[prost(bool, tag = "8")]
+        pub has_filenames: bool,
+        #[prost(bool, tag = "9")]
+        pub has_line_numbers: bool,
+        #[prost(bool, tag = "10")]
+        pub has_inline_frames: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Location {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(uint64, tag = "2")]
+        pub mapping_id: u64,
+        #[prost(uint64, tag = "3")]
+        pub address: u64,
+        /// Innermost (inlined) function first
+        #[prost(message, repeated, tag = "4")]
+        pub line: Vec<Line>,
+        #[prost(bool, tag = "5")]
+        pub is_folded: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Line {
+        #[prost(uint64, tag = "1")]
+        pub function_id: u64,
+        #[prost(int64, tag = "2")]
+        pub line: i64,
+        #[prost(int64, tag = "3")]
+        pub column: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Function {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(int64, tag = "2")]
+        pub name: i64,
+        #[prost(int64, tag = "3")]
+        pub system_name: i64,
+        #[prost(int64, tag = "4")]
+        pub filename: i64,
+        #[prost(int64, tag = "5")]
+        pub start_line: i64,
+    }
+}
+
+/// Label holding the speedscope profile (thread) name of each sample
+pub const THREAD_LABEL: &str = "thread";
+
+/// Deduplicated strings of a profile, index 0 is the empty string
+#[derive(Default)]
+struct StringTable {
+    strings: Vec<String>,
+    indexes: HashMap<String, i64>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        let mut table = Self::default();
+        table.intern("");
+        table
+    }
+
+    fn intern(&mut self, s: &str) -> i64 {
+        if let Some(index) = self.indexes.get(s) {
+            return *index;
+        }
+        self.strings.push(s.to_string());
+        self.indexes
+            .insert(s.to_string(), self.strings.len() as i64 - 1);
+        self.strings.len() as i64 - 1
+    }
+}
+
+/// Sample type and unit the weights of speedscope profiles in `unit` become,
+/// with the factor to convert them. Time units become nanoseconds of CPU time,
+/// as py-spy only records threads that are running by default.
+fn weight_sample_type(unit: &str) -> Option<(&'static str, &'static str, f64)> {
+    match unit {
+        "none" => None,
+        "bytes" => Some(("space", "bytes", 1.0)),
+        unit => seconds_per_unit(unit).map(|seconds| ("cpu", "nanoseconds", seconds * 1e9)),
+    }
+}
+
+/// Builds a pprof profile from a valid speedscope file.
+///
+/// Every frame becomes a Function (name and file) and a Location (line), every non-empty
+/// sample a Sample with a `samples` count, its weight as a second value matching the unit,
+/// and a `thread` label with its profile name. Weights of profiles without a unit are
+/// sample counts, so they become the `samples` value itself.
+/// The time range of the profiles becomes `duration_nanos`, and `time_nanos` when the file
+/// was combined on a timeline and so knows the wall-clock time of its values; otherwise
+/// speedscope values are relative and `time_nanos` stays unset, like the sampling period.
+/// All profiles must share a kind of unit.
+pub fn to_pprof(speedscope: &Speedscope) -> Result<proto::Profile, String> {
+    let mut strings = StringTable::new();
+    let mut profile = proto::Profile::default();
+
+    let sampled = speedscope
+        .profiles
+        .iter()
+        .map(|profile| profile.to_sampled())
+        .collect::<Result<Vec<_>, _>>()?;
+    let first_unit = sampled
+        .first()
+        .map_or("none", |profile| profile.unit.as_str());
+    let weight_type = weight_sample_type(first_unit).map(|(r#type, unit, _)| (r#type, unit));
+    let sample_type_of = |unit| weight_sample_type(unit).map(|(r#type, unit, _)| (r#type, unit));
+    if let Some(other) = sampled
+        .iter()
+        .find(|profile| sample_type_of(&profile.unit) != sample_type_of(first_unit))
+    {
+        return Err(format!(
+            "profile {} is in {:?}, which can't be in one pprof profile with {:?}",
+            other.name, other.unit, first_unit
+        ));
+    }
+
+    let samples_type = proto::ValueType {
+        r#type: strings.intern("samples"),
+        unit: strings.intern("count"),
+    };
+    profile.sample_type.push(samples_type);
+    if let Some((r#type, unit)) = weight_type {
+        let value_type = proto::ValueType {
+            r#type: strings.intern(r#type),
+            unit: strings.intern(unit),
+        };
+        // the default sample type is named by its string, not by its position
+        profile.default_sample_type = value_type.r#type;
+        profile.sample_type.push(value_type);
+    }
+
+    // Python code has no binary to symbolize, one mapping tells pprof all is resolved
+    profile.mapping.push(proto::Mapping {
+        id: 1,
+        filename: strings.intern("python"),
+        has_functions: true,
+        has_filenames: true,
+        has_line_numbers: true,
+        ..proto::Mapping::default()
+    });
+    let mut function_ids: HashMap<(&str, Option<&str>), u64> = HashMap::new();
+    for (i, frame) in speedscope.shared.frames.iter().enumerate() {
+        let key = (frame.name.as_str(), frame.file.as_deref());
+        let function_id = *function_ids.entry(key).or_insert_with(|| {
+            let id = profile.function.len() as u64 + 1;
+            profile.function.push(proto::Function {
+                id,
+                name: strings.intern(&frame.name),
+                system_name: strings.intern(&frame.name),
+                filename: strings.intern(frame.file.as_deref().unwrap_or("")),
+                start_line: 0,
+            });
+            id
+        });
+        profile.location.push(proto::Location {
+            id: i as u64 + 1,
+            mapping_id: 1,
+            line: vec![proto::Line {
+                function_id,
+                line: frame.line.map_or(0, i64::from),
+                column: frame.col.map_or(0, i64::from),
+            }],
+            ..proto::Location::default()
+        });
+    }
+
+    let thread_key = strings.intern(THREAD_LABEL);
+    let mut start_nanos = f64::INFINITY;
+    let mut end_nanos = f64::NEG_INFINITY;
+    for sampled in &sampled {
+        if let Some(nanos) = seconds_per_unit(&sampled.unit).map(|seconds| seconds * 1e9) {
+            start_nanos = start_nanos.min(sampled.start_value * nanos);
+            end_nanos = end_nanos.max(sampled.end_value * nanos);
+        }
+        let thread = strings.intern(&sampled.name);
+        // profiles of a combine keep their own time unit, so each converts by its own factor
+        let factor = weight_sample_type(&sampled.unit).map(|(_, _, factor)| factor);
+        for (stack, weight) in sampled.samples.iter().zip(&sampled.weights) {
+            if stack.is_empty() {
+                continue;
+            }
+            let value = match factor {
+                Some(factor) => vec![1, (weight * factor).round() as i64],
+                None => vec![weight.round() as i64],
+            };
+            profile.sample.push(proto::Sample {
+                location_id: stack.iter().rev().map(|index| *index as u64 + 1).collect(),
+                value,
+                label: vec![proto::Label {
+                    key: thread_key,
+                    str: thread,
+                    ..proto::Label::default()
+                }],
+            });
+        }
+    }
+    if start_nanos.is_finite() {
+        profile.duration_nanos = (end_nanos - start_nanos).round() as i64;
+        if let Some(nanos) = speedscope
+            .session_start()
+            .and_then(|session_start| session_start.timestamp_nanos_opt())
+        {
+            profile.time_nanos = nanos + start_nanos.round() as i64;
+        }
+    }
+
+    profile.string_table = strings.strings;
+    Ok(profile)
+}
+
+/// Encodes a speedscope file as an uncompressed profile.proto message
+pub fn encode_pprof(speedscope: &Speedscope) -> Result<Vec<u8>, String> {
+    Ok(to_pprof(speedscope)?.encode_to_vec())
+}
+
+/// How the samples of a pprof profile are split into speedscope profiles
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PprofSplit {
+    /// One profile per value of a label, such as the thread, weighted by the default sample type
+    #[default]
+    Label,
+    /// One profile per sample type, such as `samples` and `cpu`
+    SampleType,
+}
+
+/// Options for reading pprof profiles
+#[derive(Debug, Clone)]
+pub struct PprofImportOptions {
+    pub split: PprofSplit,
+    /// Label splitting samples with `PprofSplit::Label`
+    pub label: String,
+}
+
+impl Default for PprofImportOptions {
+    fn default() -> Self {
+        Self {
+            split: PprofSplit::default(),
+            label: THREAD_LABEL.to_string(),
+        }
+    }
+}
+
+/// Speedscope unit of a pprof sample type unit
+fn speedscope_unit(unit: &str) -> &str {
+    match unit {
+        "nanoseconds" | "microseconds" | "milliseconds" | "seconds" | "bytes" => unit,
+        _ => "none",
+    }
+}
+
+/// Decodes a profile.proto message, which must already be decompressed
+pub fn decode_pprof(bytes: &[u8]) -> Result<proto::Profile, String> {
+    proto::Profile::decode(bytes).map_err(|e| format!("not a pprof profile: {}", e))
+}
+
+/// Builds a speedscope file from a pprof profile.
+///
+/// Locations are expanded into one frame per line, so inlined functions get their own
+/// frame, and frames are keyed like speedscope frames so both intern together.
+/// With `PprofSplit::Label` samples without the label go into a profile named `name`.
+/// Samples with a zero value are left out.
+pub fn from_pprof(
+    profile: &proto::Profile,
+    name: &str,
+    options: &PprofImportOptions,
+) -> Result<Speedscope, String> {
+    let string = |index: i64| -> Result<&str, String> {
+        usize::try_from(index)
+            .ok()
+            .and_then(|index| profile.string_table.get(index))
+            .map(String::as_str)
+            .ok_or_else(|| format!("string {} is out of range", index))
+    };
+    let functions: HashMap<u64, &proto::Function> =
+        profile.function.iter().map(|f| (f.id, f)).collect();
+
+    let mut frames = Vec::new();
+    let mut frame_indexes: HashMap<FrameKey, i32> = HashMap::new();
+    // frames of each location, outermost first
+    let mut location_frames: HashMap<u64, Vec<i32>> = HashMap::new();
+    for location in &profile.location {
+        let mut indexes = Vec::with_capacity(location.line.len());
+        for line in location.line.iter().rev() {
+            let function = functions
+                .get(&line.function_id)
+                .ok_or_else(|| format!("function {} does not exist", line.function_id))?;
+            let file = string(function.filename)?;
+            let frame = Frame {
+                name: string(function.name)?.to_string(),
+                file: (!file.is_empty()).then(|| file.to_string()),
+                line: u32::try_from(line.line).ok().filter(|line| *line > 0),
+                col: u32::try_from(line.column).ok().filter(|column| *column > 0),
+                ..Frame::default()
+            };
+            let index = *frame_indexes.entry(frame.key()).or_insert_with(|| {
+                frames.push(frame);
+                frames.len() as i32 - 1
+            });
+            indexes.push(index);
+        }
+        location_frames.insert(location.id, indexes);
+    }
+
+    let sample_types = profile
+        .sample_type
+        .iter()
+        .map(|t| Ok((string(t.r#type)?, speedscope_unit(string(t.unit)?))))
+        .collect::<Result<Vec<_>, String>>()?;
+    if sample_types.is_empty() {
+        return Err("pprof profile has no sample types".to_string());
+    }
+    let default_type = match profile.default_sample_type {
+        0 => sample_types.len() - 1,
+        index => sample_types
+            .iter()
+            .position(|(r#type, _)| string(index).is_ok_and(|name| name == *r#type))
+            .unwrap_or(sample_types.len() - 1),
+    };
+    let label_key = profile
+        .string_table
+        .iter()
+        .position(|s| *s == options.label)
+        .map(|index| index as i64);
+
+    let mut profiles: Vec<SampledProfile> = Vec::new();
+    let mut profile_indexes: HashMap<(String, usize), usize> = HashMap::new();
+    for sample in &profile.sample {
+        let mut stack = Vec::new();
+        for location_id in sample.location_id.iter().rev() {
+            let frames = location_frames
+                .get(location_id)
+                .ok_or_else(|| format!("location {} does not exist", location_id))?;
+            stack.extend_from_slice(frames);
+        }
+        let splits: Vec<(String, usize)> = match options.split {
+            PprofSplit::SampleType => (0..sample_types.len())
+                .map(|index| (sample_types[index].0.to_string(), index))
+                .collect(),
+            PprofSplit::Label => {
+                let label = sample
+                    .label
+                    .iter()
+                    .find(|label| Some(label.key) == label_key && label.str != 0);
+                let profile_name = match label {
+                    Some(label) => string(label.str)?.to_string(),
+                    None => name.to_string(),
+                };
+                vec![(profile_name, default_type)]
+            }
+        };
+        for (profile_name, type_index) in splits {
+            let value = *sample.value.get(type_index).ok_or_else(|| {
+                format!(
+                    "sample has {} values but {} sample types",
+                    sample.value.len(),
+                    sample_types.len()
+                )
+            })?;
+            if value < 0 {
+                return Err(format!("sample has negative value {}", value));
+            }
+            if value == 0 {
+                continue;
+            }
+            let index = *profile_indexes
+                .entry((profile_name, type_index))
+                .or_insert_with_key(|(profile_name, type_index)| {
+                    profiles.push(SampledProfile {
+                        name: profile_name.clone(),
+                        unit: sample_types[*type_index].1.to_string(),
+                        ..SampledProfile::default()
+                    });
+                    profiles.len() - 1
+                });
+            let profile = &mut profiles[index];
+            profile.samples.push(stack.clone());
+            profile.weights.push(value as f64);
+            profile.end_value += value as f64;
+        }
+    }
+
+    Ok(Speedscope {
+        profiles: profiles.into_iter().map(Profile::Sampled).collect(),
+        shared: Shared {
+            frames,
+            extra: Extra::new(),
+        },
+        schema: SCHEMA.to_string(),
+        exporter: None,
+        name: Some(name.to_string()),
+        active_profile_index: None,
+        extra: Extra::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(name: &str, file: &str, line: u32) -> Frame {
+        Frame {
+            name: name.to_string(),
+            file: Some(file.to_string()),
+            line: Some(line),
+            ..Frame::default()
+        }
+    }
+
+    fn input(unit: &str) -> Speedscope {
+        Speedscope {
+            profiles: vec![Profile::Sampled(SampledProfile {
+                name: "MainThread".to_string(),
+                unit: unit.to_string(),
+                start_value: 0.0,
+                end_value: 0.04,
+                samples: vec![vec![0, 1], vec![], vec![0, 2]],
+                weights: vec![0.01, 0.02, 0.01],
+                ..SampledProfile::default()
+            })],
+            shared: Shared {
+                frames: vec![
+                    frame("<module>", "app.py", 1),
+                    frame("handle", "views.py", 12),
+                    frame("handle", "views.py", 15),
+                ],
+                ..Shared::default()
+            },
+            schema: String::new(),
+            exporter: None,
+            name: None,
+            active_profile_index: None,
+            extra: Extra::new(),
+        }
+    }
+
+    fn string(profile: &proto::Profile, index: i64) -> &str {
+        &profile.string_table[index as usize]
+    }
+
+    #[test]
+    fn test_pprof_round_trips_through_protobuf() {
+        let bytes = encode_pprof(&input("seconds")).unwrap();
+        let profile = proto::Profile::decode(bytes.as_slice()).unwrap();
+
+        assert_eq!(profile.string_table[0], "");
+        let sample_types: Vec<(&str, &str)> = profile
+            .sample_type
+            .iter()
+            .map(|t| (string(&profile, t.r#type), string(&profile, t.unit)))
+            .collect();
+        assert_eq!(
+            sample_types,
+            vec![("samples", "count"), ("cpu", "nanoseconds")]
+        );
+        assert_eq!(string(&profile, profile.default_sample_type), "cpu");
+        assert_eq!(profile.duration_nanos, 40_000_000);
+
+        // the empty sample is left out and stacks are leaf first
+        assert_eq!(profile.sample.len(), 2);
+        assert_eq!(profile.sample[0].location_id, vec![2, 1]);
+        assert_eq!(profile.sample[0].value, vec![1, 10_000_000]);
+        let label = &profile.sample[0].label[0];
+        assert_eq!(string(&profile, label.key), "thread");
+        assert_eq!(string(&profile, label.str), "MainThread");
+    }
+
+    #[test]
+    fn test_relative_start_is_not_a_recording_time() {
+        let mut speedscope = input("seconds");
+        if let Profile::Sampled(profile) = &mut speedscope.profiles[0] {
+            profile.start_value = 5.0;
+            profile.end_value = 5.04;
+        }
+
+        let profile = to_pprof(&speedscope).unwrap();
+
+        assert_eq!(profile.time_nanos, 0);
+        assert_eq!(profile.duration_nanos, 40_000_000);
+        assert_eq!(profile.period_type, None);
+        assert_eq!(profile.period, 0);
+    }
+
+    #[test]
+    fn test_frames_map_to_functions_and_locations() {
+        let profile = to_pprof(&input("milliseconds")).unwrap();
+
+        // both lines of handle share one function
+        assert_eq!(profile.function.len(), 2);
+        assert_eq!(profile.location.len(), 3);
+        let line = &profile.location[2].line[0];
+        assert_eq!(line.line, 15);
+        let function = &profile.function[line.function_id as usize - 1];
+        assert_eq!(string(&profile, function.name), "handle");
+        assert_eq!(string(&profile, function.filename), "views.py");
+    }
+
+    #[test]
+    fn test_session_start_becomes_recording_time() {
+        let mut speedscope = input("seconds");
+        if let Profile::Sampled(profile) = &mut speedscope.profiles[0] {
+            profile.start_value = 5.0;
+            profile.end_value = 5.04;
+        }
+        speedscope.extra.insert(
+            crate::speedscope_format::SESSION_START.to_string(),
+            "2025-01-01T00:00:10+00:00".into(),
+        );
+
+        let profile = to_pprof(&speedscope).unwrap();
+
+        assert_eq!(profile.time_nanos, 1_735_689_615_000_000_000);
+        assert_eq!(profile.duration_nanos, 40_000_000);
+    }
+
+    #[test]
+    fn test_counts_have_only_the_samples_type() {
+        let mut speedscope = input("none");
+        if let Profile::Sampled(profile) = &mut speedscope.profiles[0] {
+            profile.end_value = 55.0;
+            profile.weights = vec![50.0, 2.0, 3.0];
+        }
+
+        let profile = to_pprof(&speedscope).unwrap();
+
+        assert_eq!(profile.sample_type.len(), 1);
+        let values: Vec<&[i64]> = profile
+            .sample
+            .iter()
+            .map(|sample| sample.value.as_slice())
+            .collect();
+        assert_eq!(values, vec![&[50][..], &[3][..]]);
+        assert_eq!(profile.duration_nanos, 0);
+    }
+
+    #[test]
+    fn test_mixed_kinds_of_units_are_rejected() {
+        let mut speedscope = input("seconds");
+        let mut other = speedscope.profiles[0].clone();
+        if let Profile::Sampled(profile) = &mut other {
+            profile.unit = "bytes".to_string();
+        }
+        speedscope.profiles.push(other);
+
+        assert!(to_pprof(&speedscope).is_err());
+    }
+
+    #[test]
+    fn test_each_profile_converts_its_own_time_unit() {
+        let mut speedscope = input("seconds");
+        let mut other = speedscope.profiles[0].clone();
+        if let Profile::Sampled(profile) = &mut other {
+            profile.name = "worker".to_string();
+            profile.unit = "milliseconds".to_string();
+            profile.end_value = 40.0;
+            profile.weights = vec![10.0, 20.0, 10.0];
+        }
+        speedscope.profiles.push(other);
+
+        let profile = to_pprof(&speedscope).unwrap();
+
+        let values: Vec<i64> = profile
+            .sample
+            .iter()
+            .map(|sample| sample.value[1])
+            .collect();
+        assert_eq!(values, vec![10_000_000; 4]);
+    }
+
+    fn sampled(profile: &Profile) -> &SampledProfile {
+        match profile {
+            Profile::Sampled(profile) => profile,
+            Profile::Evented(_) => panic!("expected a sampled profile"),
+        }
+    }
+
+    #[test]
+    fn test_exported_pprof_imports_back() {
+        let mut original = input("seconds");
+        original.shared.frames[1].col = Some(8);
+        let pprof = decode_pprof(&encode_pprof(&original).unwrap()).unwrap();
+
+        let imported = from_pprof(&pprof, "profile.pb", &PprofImportOptions::default()).unwrap();
+
+        assert!(imported.validate().is_ok());
+        let keys: Vec<FrameKey> = imported.shared.frames.iter().map(Frame::key).collect();
+        let original_keys: Vec<FrameKey> = original.shared.frames.iter().map(Frame::key).collect();
+        assert_eq!(keys, original_keys);
+        let profile = sampled(&imported.profiles[0]);
+        assert_eq!(profile.name, "MainThread");
+        assert_eq!(profile.unit, "nanoseconds");
+        assert_eq!(profile.samples, vec![vec![0, 1], vec![0, 2]]);
+        assert_eq!(profile.weights, vec![10_000_000.0, 10_000_000.0]);
+    }
+
+    #[test]
+    fn test_split_by_sample_type() {
+        let pprof = to_pprof(&input("seconds")).unwrap();
+        let options = PprofImportOptions {
+            split: PprofSplit::SampleType,
+            ..PprofImportOptions::default()
+        };
+
+        let imported = from_pprof(&pprof, "profile.pb", &options).unwrap();
+
+        let profiles: Vec<(&str, &str)> = imported
+            .profiles
+            .iter()
+            .map(|p| (sampled(p).name.as_str(), sampled(p).unit.as_str()))
+            .collect();
+        assert_eq!(profiles, vec![("samples", "none"), ("cpu", "nanoseconds")]);
+        assert_eq!(sampled(&imported.profiles[0]).weights, vec![1.0, 1.0]);
+    }
+
+    /// A Go CPU profile: no thread label, an inlined function and a frame without a file
+    fn go_profile() -> proto::Profile {
+        let strings = [
+            "",
+            "samples",
+            "count",
+            "cpu",
+            "nanoseconds",
+            "main.main",
+            "main.go",
+            "main.work",
+            "runtime.mcall",
+        ];
+        proto::Profile {
+            sample_type: vec![
+                proto::ValueType { r#type: 1, unit: 2 },
+                proto::ValueType { r#type: 3, unit: 4 },
+            ],
+            sample: vec![proto::Sample {
+                location_id: vec![2, 1],
+                value: vec![3, 30_000_000],
+                label: vec![],
+            }],
+            location: vec![
+                proto::Location {
+                    id: 1,
+                    line: vec![
+                        // main.work inlined into main.main
+                        proto::Line {
+                            function_id: 2,
+                            line: 20,
+                            column: 0,
+                        },
+                        proto::Line {
+                            function_id: 1,
+                            line: 10,
+                            column: 0,
+                        },
+                    ],
+                    ..proto::Location::default()
+                },
+                proto::Location {
+                    id: 2,
+                    line: vec![proto::Line {
+                        function_id: 3,
+                        line: 0,
+                        column: 0,
+                    }],
+                    ..proto::Location::default()
+                },
+            ],
+            function: vec![
+                proto::Function {
+                    id: 1,
+                    name: 5,
+                    filename: 6,
+                    ..proto::Function::default()
+                },
+                proto::Function {
+                    id: 2,
+                    name: 7,
+                    filename: 6,
+                    ..proto::Function::default()
+                },
+                proto::Function {
+                    id: 3,
+                    name: 8,
+                    filename: 0,
+                    ..proto::Function::default()
+                },
+            ],
+            string_table: strings.iter().map(|s| s.to_string()).collect(),
+            ..proto::Profile::default()
+        }
+    }
+
+    #[test]
+    fn test_go_profile_without_labels() {
+        let imported =
+            from_pprof(&go_profile(), "cpu.pprof", &PprofImportOptions::default()).unwrap();
+
+        let names: Vec<&str> = imported
+            .shared
+            .frames
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["main.main", "main.work", "runtime.mcall"]);
+        assert_eq!(imported.shared.frames[2].file, None);
+        assert_eq!(imported.shared.frames[2].line, None);
+        let profile = sampled(&imported.profiles[0]);
+        assert_eq!(profile.name, "cpu.pprof");
+        assert_eq!(profile.samples, vec![vec![0, 1, 2]]);
+        assert_eq!(profile.weights, vec![30_000_000.0]);
+    }
+
+    #[test]
+    fn test_missing_location_is_an_error() {
+        let mut profile = go_profile();
+        profile.sample[0].location_id.push(9);
+
+        assert!(
+            from_pprof(&profile, "cpu.pprof", &PprofImportOptions::default())
+                .unwrap_err()
+                .contains("location 9")
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use crate::chunk_manifest;
+use regex::Regex;
+use std::sync::OnceLock;
+
+/// Name of the single profile when everything is grouped together
+const ALL_PROFILES_NAME: &str = "all threads";
+
+/// How profiles from different inputs are matched up to be merged
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Grouping {
+    /// Profiles with exactly the same name are merged
+    #[default]
+    Exact,
+    /// Profiles are merged by the first capture group of `--group-regex`, or by the whole
+    /// match if it has no groups. Names that don't match are grouped by their exact name
+    Regex,
+    /// Profiles are merged by thread name, ignoring process and thread ids.
+    /// Threads without a name are merged by thread id
+    ThreadName,
+    /// Every profile is merged into one
+    All,
+    /// All profiles of an input file are merged into one, named after the file
+    PerFile,
+}
+
+/// A grouping strategy ready to be applied to profile names
+#[derive(Debug, Clone, Default)]
+pub enum GroupBy {
+    #[default]
+    Exact,
+    Regex(Regex),
+    ThreadName,
+    All,
+    PerFile,
+}
+
+impl GroupBy {
+    /// Builds the strategy, `regex` is required for `Grouping::Regex` and ignored otherwise
+    pub fn new(grouping: Grouping, regex: Option<Regex>) -> Result<Self, String> {
+        Ok(match grouping {
+            Grouping::Exact => GroupBy::Exact,
+            Grouping::Regex => {
+                GroupBy::Regex(regex.ok_or_else(|| "grouping by regex needs a regex".to_string())?)
+            }
+            Grouping::ThreadName => GroupBy::ThreadName,
+            Grouping::All => GroupBy::All,
+            Grouping::PerFile => GroupBy::PerFile,
+        })
+    }
+
+    /// Name of the merged profile that the profile `name` from the input at `path` goes into
+    pub fn group_name(&self, path: &str, name: &str) -> String {
+        match self {
+            GroupBy::Exact => name.to_string(),
+            GroupBy::Regex(regex) => regex
+                .captures(name)
+                .and_then(|captures| captures.get(1).or_else(|| captures.get(0)))
+                .map_or_else(|| name.to_string(), |group| group.as_str().to_string()),
+            GroupBy::ThreadName => thread_name(name),
+            GroupBy::All => ALL_PROFILES_NAME.to_string(),
+            GroupBy::PerFile => chunk_manifest::file_name(path),
+        }
+    }
+}
+
+/// The thread name of a py-spy profile name, without the process and thread ids.
+///
+/// py-spy names profiles `Process <pid> Thread <tid> "<thread name>"`, where the tid is
+/// decimal or hex and the thread name is only present when py-spy could read it.
+/// Names without a thread name drop only the process id, so unnamed threads stay apart
+/// by their thread id. Other names are kept as is.
+fn thread_name(name: &str) -> String {
+    static QUOTED_NAME: OnceLock<Regex> = OnceLock::new();
+    static PROCESS_ID: OnceLock<Regex> = OnceLock::new();
+    let quoted_name = QUOTED_NAME.get_or_init(|| Regex::new(r#"Thread \S+ "(.*)"$"#).unwrap());
+    if let Some(captures) = quoted_name.captures(name) {
+        return captures[1].to_string();
+    }
+    let process_id =
+        PROCESS_ID.get_or_init(|| Regex::new(r"\bProcess (0x[0-9a-fA-F]+|\d+) ").unwrap());
+    process_id.replace_all(name, "").into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAIN_BEFORE_RESTART: &str = r#"Process 1 Thread 0x7F4E1A2B3740 "MainThread""#;
+    const MAIN_AFTER_RESTART: &str = r#"Process 7 Thread 0x7F9C55D01740 "MainThread""#;
+    const WORKER: &str = r#"Process 7 Thread 0x7F9C4F3FE640 "ThreadPoolExecutor-0_0""#;
+    const UNNAMED_BEFORE_RESTART: &str = "Process 1 Thread 140234567890112";
+    const UNNAMED_AFTER_RESTART: &str = "Process 7 Thread 139871234510656";
+    const UNNAMED_OTHER: &str = "Process 7 Thread 139871226117952";
+
+    #[test]
+    fn test_exact_keeps_names() {
+        let group_by = GroupBy::Exact;
+
+        assert_ne!(
+            group_by.group_name("a.json", MAIN_BEFORE_RESTART),
+            group_by.group_name("b.json", MAIN_AFTER_RESTART)
+        );
+        assert_eq!(group_by.group_name("a.json", WORKER), WORKER);
+    }
+
+    #[test]
+    fn test_thread_name_ignores_ids() {
+        let group_by = GroupBy::ThreadName;
+
+        assert_eq!(
+            group_by.group_name("a.json", MAIN_BEFORE_RESTART),
+            "MainThread"
+        );
+        assert_eq!(
+            group_by.group_name("b.json", MAIN_AFTER_RESTART),
+            "MainThread"
+        );
+        assert_eq!(
+            group_by.group_name("b.json", WORKER),
+            "ThreadPoolExecutor-0_0"
+        );
+        assert_eq!(
+            group_by.group_name("a.json", UNNAMED_BEFORE_RESTART),
+            "Thread 140234567890112"
+        );
+        assert_ne!(
+            group_by.group_name("b.json", UNNAMED_AFTER_RESTART),
+            group_by.group_name("b.json", UNNAMED_OTHER)
+        );
+        assert_eq!(group_by.group_name("c.json", "cpu"), "cpu");
+    }
+
+    #[test]
+    fn test_regex_uses_first_capture_group() {
+        let group_by = GroupBy::new(
+            Grouping::Regex,
+            Some(Regex::new(r#""(\w+?)(-\d+_\d+)?"$"#).unwrap()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            group_by.group_name("a.json", MAIN_BEFORE_RESTART),
+            "MainThread"
+        );
+        assert_eq!(group_by.group_name("b.json", WORKER), "ThreadPoolExecutor");
+        assert_eq!(
+            group_by.group_name("a.json", UNNAMED_BEFORE_RESTART),
+            UNNAMED_BEFORE_RESTART
+        );
+    }
+
+    #[test]
+    fn test_regex_without_groups_uses_whole_match() {
+        let group_by =
+            GroupBy::new(Grouping::Regex, Some(Regex::new(r"^Process \d+").unwrap())).unwrap();
+
+        assert_eq!(
+            group_by.group_name("a.json", MAIN_BEFORE_RESTART),
+            "Process 1"
+        );
+        assert_eq!(group_by.group_name("b.json", WORKER), "Process 7");
+    }
+
+    #[test]
+    fn test_regex_grouping_requires_regex() {
+        assert!(GroupBy::new(Grouping::Regex, None).is_err());
+    }
+
+    #[test]
+    fn test_all_and_per_file() {
+        assert_eq!(
+            GroupBy::All.group_name("a.json", MAIN_BEFORE_RESTART),
+            GroupBy::All.group_name("b.json", WORKER)
+        );
+        assert_eq!(
+            GroupBy::PerFile.group_name("./profiling_results/pyspy_output_1.json", WORKER),
+            "pyspy_output_1.json"
+        );
+        assert_eq!(
+            GroupBy::PerFile.group_name(
+                "./profiling_results/pyspy_output_1.json",
+                MAIN_AFTER_RESTART
+            ),
+            "pyspy_output_1.json"
+        );
+    }
+}
@@ -0,0 +1,461 @@
+use crate::combine_speedscope::{combine_in_memory, CombineConfig, CombineSummary, ImportOptions};
+use crate::error::{Error, Result};
+use crate::file_io;
+use crate::flamegraph::format_value;
+use crate::frame_normalization::{module_name, FrameNormalization};
+use crate::profile_grouping::GroupBy;
+use crate::speedscope_format::{seconds_per_unit, Frame, Speedscope};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Write};
+
+/// What the rows of a report are
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportGroup {
+    /// One row per function, by name and file
+    #[default]
+    Function,
+    /// One row per source file
+    File,
+    /// One row per Python module, derived from the file path
+    Module,
+}
+
+/// Which weight the rows are ranked by
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReportSort {
+    /// Time spent in the row itself, its self weight
+    #[default]
+    #[value(name = "self")]
+    SelfWeight,
+    /// Time spent in the row and everything it called, its inclusive weight
+    Total,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// Aligned table for the terminal
+    #[default]
+    Text,
+    Json,
+    Csv,
+}
+
+/// Options for building a report
+#[derive(Debug, Default)]
+pub struct ReportConfig {
+    pub group: ReportGroup,
+    pub sort: ReportSort,
+    /// Number of rows kept, all rows when 0
+    pub top: usize,
+    pub format: ReportFormat,
+    /// Number of threads used to parse and remap inputs, defaults to the number of cores
+    pub jobs: Option<usize>,
+    /// Leave out inputs that can't be read or fail validation instead of failing
+    pub skip_invalid: bool,
+    pub normalization: FrameNormalization,
+    pub import: ImportOptions,
+    /// Replace the output file if it already exists
+    pub force: bool,
+}
+
+/// Weights of one function, file or module. Inclusive values count a row once per
+/// sample even when it appears several times in the stack, such as in recursion.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ReportRow {
+    /// Function name, file path or module name depending on `ReportGroup`
+    pub name: String,
+    /// File of the function, only for `ReportGroup::Function`
+    pub file: Option<String>,
+    pub self_weight: f64,
+    pub self_percent: f64,
+    pub total_weight: f64,
+    pub total_percent: f64,
+    pub self_samples: usize,
+    pub total_samples: usize,
+}
+
+/// The hottest rows of the combined inputs
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub group: ReportGroup,
+    /// Unit of the weights, `seconds` when all profiles are measured in time
+    pub unit: String,
+    /// Weight of all non-empty samples, the base of the percentages
+    pub total_weight: f64,
+    pub samples: usize,
+    pub rows: Vec<ReportRow>,
+}
+
+/// Name and file of the row a frame belongs to
+fn row_key(frame: &Frame, group: ReportGroup) -> (String, Option<String>) {
+    match (group, &frame.file) {
+        (ReportGroup::Function, file) => (frame.name.clone(), file.clone()),
+        (ReportGroup::File, Some(file)) => (file.clone(), None),
+        (ReportGroup::Module, Some(file)) => (module_name(file), None),
+        (_, None) => ("<unknown>".to_string(), None),
+    }
+}
+
+/// Computes self and inclusive weights of every row of a valid speedscope file.
+/// Time weights are converted to seconds, empty samples are left out.
+pub fn build_report(
+    speedscope: &Speedscope,
+    group: ReportGroup,
+    sort: ReportSort,
+    top: usize,
+) -> std::result::Result<Report, String> {
+    let sampled = speedscope
+        .profiles
+        .iter()
+        .map(|profile| profile.to_sampled())
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let all_time = sampled
+        .iter()
+        .all(|profile| seconds_per_unit(&profile.unit).is_some());
+    let unit = match sampled.first() {
+        Some(_) if all_time => "seconds".to_string(),
+        Some(profile) => profile.unit.clone(),
+        None => "none".to_string(),
+    };
+    if let Some(other) = sampled
+        .iter()
+        .find(|profile| !all_time && profile.unit != unit)
+    {
+        return Err(format!(
+            "profile {} is in {:?}, which can't be summed with {:?}",
+            other.name, other.unit, unit
+        ));
+    }
+
+    let keys: Vec<(String, Option<String>)> = speedscope
+        .shared
+        .frames
+        .iter()
+        .map(|frame| row_key(frame, group))
+        .collect();
+    let mut rows: BTreeMap<&(String, Option<String>), ReportRow> = BTreeMap::new();
+    let mut total_weight = 0.0;
+    let mut samples = 0;
+    for profile in &sampled {
+        let scale = if all_time {
+            seconds_per_unit(&profile.unit).unwrap_or(1.0)
+        } else {
+            1.0
+        };
+        for (sample, weight) in profile.samples.iter().zip(&profile.weights) {
+            if sample.is_empty() {
+                continue;
+            }
+            let weight = weight * scale;
+            total_weight += weight;
+            samples += 1;
+            let mut seen = HashSet::new();
+            for (depth, index) in sample.iter().enumerate() {
+                let key = &keys[*index as usize];
+                let row = rows.entry(key).or_insert_with(|| ReportRow {
+                    name: key.0.clone(),
+                    file: key.1.clone(),
+                    self_weight: 0.0,
+                    self_percent: 0.0,
+                    total_weight: 0.0,
+                    total_percent: 0.0,
+                    self_samples: 0,
+                    total_samples: 0,
+                });
+                if seen.insert(key) {
+                    row.total_weight += weight;
+                    row.total_samples += 1;
+                }
+                if depth == sample.len() - 1 {
+                    row.self_weight += weight;
+                    row.self_samples += 1;
+                }
+            }
+        }
+    }
+
+    let percent = |weight: f64| {
+        if total_weight > 0.0 {
+            weight / total_weight * 100.0
+        } else {
+            0.0
+        }
+    };
+    let mut rows: Vec<ReportRow> = rows
+        .into_values()
+        .map(|row| ReportRow {
+            self_percent: percent(row.self_weight),
+            total_percent: percent(row.total_weight),
+            ..row
+        })
+        .collect();
+    // stable, so equal weights keep the name order of the map
+    rows.sort_by(|a, b| match sort {
+        ReportSort::SelfWeight => b
+            .self_weight
+            .total_cmp(&a.self_weight)
+            .then_with(|| b.total_weight.total_cmp(&a.total_weight)),
+        ReportSort::Total => b
+            .total_weight
+            .total_cmp(&a.total_weight)
+            .then_with(|| b.self_weight.total_cmp(&a.self_weight)),
+    });
+    if top > 0 {
+        rows.truncate(top);
+    }
+    Ok(Report {
+        group,
+        unit,
+        total_weight,
+        samples,
+        rows,
+    })
+}
+
+fn write_text(report: &Report, out: &mut impl Write) -> io::Result<()> {
+    writeln!(
+        out,
+        "{} samples, {}",
+        report.samples,
+        format_value(report.total_weight, &report.unit)
+    )?;
+    let weight = |value: f64| match report.unit.as_str() {
+        "seconds" => format!("{:.3}s", value),
+        _ => format!("{}", value),
+    };
+    let header = match report.group {
+        ReportGroup::Function => "function",
+        ReportGroup::File => "file",
+        ReportGroup::Module => "module",
+    };
+    writeln!(
+        out,
+        "{:>8} {:>12} {:>8} {:>12} {:>9}  {}",
+        "self%", "self", "total%", "total", "samples", header
+    )?;
+    for row in &report.rows {
+        let location = row
+            .file
+            .as_ref()
+            .map(|file| format!(" ({})", file))
+            .unwrap_or_default();
+        writeln!(
+            out,
+            "{:>7.2}% {:>12} {:>7.2}% {:>12} {:>9}  {}{}",
+            row.self_percent,
+            weight(row.self_weight),
+            row.total_percent,
+            weight(row.total_weight),
+            row.total_samples,
+            row.name,
+            location
+        )?;
+    }
+    Ok(())
+}
+
+/// Quotes a CSV field when it contains a separator, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn write_csv(report: &Report, out: &mut impl Write) -> io::Result<()> {
+    writeln!(
+        out,
+        "name,file,self_weight,self_percent,total_weight,total_percent,self_samples,total_samples,unit"
+    )?;
+    for row in &report.rows {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{}",
+            csv_field(&row.name),
+            csv_field(row.file.as_deref().unwrap_or("")),
+            row.self_weight,
+            row.self_percent,
+            row.total_weight,
+            row.total_percent,
+            row.self_samples,
+            row.total_samples,
+            csv_field(&report.unit)
+        )?;
+    }
+    Ok(())
+}
+
+pub fn write_report(report: &Report, format: ReportFormat, out: &mut impl Write) -> io::Result<()> {
+    match format {
+        ReportFormat::Text => write_text(report, out),
+        ReportFormat::Csv => write_csv(report, out),
+        ReportFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, report)?;
+            writeln!(out)
+        }
+    }
+}
+
+/// Summarizes where time goes in one or more profiles and writes the top rows to
+/// `output_path`, `-` for stdout. The inputs are combined into one profile first,
+/// like `combine-speedscope-files --group-by all`, so all threads are summed.
+pub fn entry_point(
+    input_paths: &[String],
+    output_path: &str,
+    config: &ReportConfig,
+) -> Result<CombineSummary> {
+    file_io::check_overwrite(output_path, config.force)?;
+    let combine_config = CombineConfig {
+        jobs: config.jobs,
+        skip_invalid: config.skip_invalid,
+        group_by: GroupBy::All,
+        normalization: config.normalization.clone(),
+        import: config.import.clone(),
+        ..CombineConfig::default()
+    };
+    let (combined, summary) = combine_in_memory(input_paths, output_path, &combine_config)?;
+    let report = build_report(&combined, config.group, config.sort, config.top)
+        .map_err(|reason| Error::invalid_profile(output_path, reason))?;
+    let mut output = file_io::create(output_path, config.force)?;
+    write_report(&report, config.format, &mut output)
+        .and_then(|_| output.finish())
+        .map_err(|e| Error::io(output_path, e))?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::speedscope_format::{Extra, Profile, SampledProfile, Shared};
+
+    fn frame(name: &str, file: &str, line: u32) -> Frame {
+        Frame {
+            name: name.to_string(),
+            file: Some(file.to_string()),
+            line: Some(line),
+            ..Frame::default()
+        }
+    }
+
+    fn input() -> Speedscope {
+        Speedscope {
+            profiles: vec![Profile::Sampled(SampledProfile {
+                name: "MainThread".to_string(),
+                unit: "milliseconds".to_string(),
+                end_value: 100.0,
+                samples: vec![vec![0, 1, 2], vec![0, 1, 3], vec![0, 1, 1], vec![0], vec![]],
+                weights: vec![40.0, 30.0, 20.0, 10.0, 50.0],
+                ..SampledProfile::default()
+            })],
+            shared: Shared {
+                frames: vec![
+                    frame("main", "/app/main.py", 1),
+                    frame("handle", "/app/views.py", 10),
+                    frame(
+                        "query",
+                        "/venv/lib/python3.11/site-packages/django/db.py",
+                        5,
+                    ),
+                    frame("handle", "/app/views.py", 12),
+                ],
+                extra: Extra::new(),
+            },
+            schema: String::new(),
+            exporter: None,
+            name: None,
+            active_profile_index: None,
+            extra: Extra::new(),
+        }
+    }
+
+    fn rounded(rows: &[ReportRow]) -> Vec<(&str, f64, f64, usize)> {
+        rows.iter()
+            .map(|row| {
+                (
+                    row.name.as_str(),
+                    (row.self_percent * 100.0).round() / 100.0,
+                    (row.total_percent * 100.0).round() / 100.0,
+                    row.total_samples,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_functions_have_self_and_inclusive_weights() {
+        let report =
+            build_report(&input(), ReportGroup::Function, ReportSort::SelfWeight, 0).unwrap();
+
+        assert_eq!(report.samples, 4);
+        assert!((report.total_weight - 0.1).abs() < 1e-12);
+        assert_eq!(report.unit, "seconds");
+        // both lines of handle are one function, and recursion is counted once
+        assert_eq!(
+            rounded(&report.rows),
+            vec![
+                ("handle", 50.0, 90.0, 3),
+                ("query", 40.0, 40.0, 1),
+                ("main", 10.0, 100.0, 4),
+            ]
+        );
+        assert_eq!(report.rows[0].self_samples, 2);
+        assert_eq!(report.rows[0].file.as_deref(), Some("/app/views.py"));
+    }
+
+    #[test]
+    fn test_sort_by_total_and_top() {
+        let report = build_report(&input(), ReportGroup::Function, ReportSort::Total, 2).unwrap();
+
+        let names: Vec<&str> = report.rows.iter().map(|row| row.name.as_str()).collect();
+        assert_eq!(names, vec!["main", "handle"]);
+    }
+
+    #[test]
+    fn test_group_by_module() {
+        let report =
+            build_report(&input(), ReportGroup::Module, ReportSort::SelfWeight, 0).unwrap();
+
+        assert_eq!(
+            rounded(&report.rows),
+            vec![
+                ("app.views", 50.0, 90.0, 3),
+                ("django.db", 40.0, 40.0, 1),
+                ("app.main", 10.0, 100.0, 4),
+            ]
+        );
+        assert_eq!(report.rows[0].file, None);
+    }
+
+    #[test]
+    fn test_text_json_and_csv_output() {
+        let report =
+            build_report(&input(), ReportGroup::Function, ReportSort::SelfWeight, 1).unwrap();
+        let write = |format| {
+            let mut out = Vec::new();
+            write_report(&report, format, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let text = write(ReportFormat::Text);
+        assert!(text.starts_with("4 samples, 0.100 s\n"));
+        assert!(text.contains(
+            "  50.00%       0.050s   90.00%       0.090s         3  handle (/app/views.py)"
+        ));
+        let json: serde_json::Value = serde_json::from_str(&write(ReportFormat::Json)).unwrap();
+        assert_eq!(json["group"], "function");
+        assert_eq!(json["rows"][0]["total_samples"], 3);
+        let csv = write(ReportFormat::Csv);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].starts_with("handle,/app/views.py,0.05,50,"));
+    }
+
+    #[test]
+    fn test_csv_fields_are_quoted() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a, \"b\""), "\"a, \"\"b\"\"\"");
+    }
+}
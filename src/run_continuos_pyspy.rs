@@ -1,3 +1,7 @@
+use crate::chunk_manifest::{self, ManifestEntry};
+use crate::error::{Error, Result};
+use crate::event_log::{Event, EventLog};
+use crate::file_io::{self, Compression};
 use chrono::Utc;
 use std::fs;
 use std::process::Command;
@@ -9,6 +13,7 @@ struct ProfilerConfig {
     pub duration_seconds: u16,
     pub num_of_samples: u16,
     pub local_output_dir: String,
+    pub compression: Compression,
 }
 
 /// Runs py-spy continuously in chunks, copying the results back to the local machine.
@@ -20,74 +25,119 @@ struct ProfilerConfig {
 /// * `pod_name` - The name of the pod to profile
 /// * `namespace` - The namespace of the pod
 /// * `duration_seconds` - The duration of each py-spy run in seconds
+/// * `num_of_samples` - The number of py-spy runs (chunks) to take
+/// * `compression` - How to compress each chunk once it is copied
+/// * `log` - Where progress events are reported
 ///
 /// # Example
 ///
 /// ```rust
 /// use run_continuos_pyspy::run_continuos_pyspy;
 ///
-/// run_continuos_pyspy("my-pod", "default", 60);
+/// run_continuos_pyspy("my-pod", "default", 60, 4, Compression::None, &EventLog::new(LogFormat::Text));
 /// ```
 ///
 /// This will run py-spy for 60 seconds in 4 chunks, copying the results back to the local machine.
-/// The results will be saved in the `profiling_results` directory.
+/// The results will be saved in the `profiling_results` directory, along with a `manifest.jsonl`
+/// recording when each chunk started, which `combine-speedscope-files --timeline` uses.
 pub fn run_continuos_pyspy(
     pod_name: String,
     namespace: String,
     duration_seconds: u16,
     num_of_samples: u16,
-) -> Result<(), Box<dyn std::error::Error>> {
+    compression: Compression,
+    log: &EventLog,
+) -> Result<()> {
     let config = ProfilerConfig {
         pod_name: pod_name.to_string(),
         namespace: namespace.to_string(),
-        duration_seconds: duration_seconds,
-        num_of_samples: num_of_samples,
+        duration_seconds,
+        num_of_samples,
         local_output_dir: "./profiling_results".to_string(),
+        compression,
     };
 
-    fs::create_dir_all(&config.local_output_dir).unwrap();
+    fs::create_dir_all(&config.local_output_dir)
+        .map_err(|e| Error::io(&config.local_output_dir, e))?;
 
-    ensure_py_spy_installed(&config)?;
+    log.emit(Event::SessionStarted {
+        pod_name: config.pod_name.clone(),
+        namespace: config.namespace.clone(),
+        duration_seconds: config.duration_seconds,
+        num_of_samples: config.num_of_samples,
+        output_dir: config.local_output_dir.clone(),
+    });
+
+    ensure_py_spy_installed(&config, log)?;
 
-    let mut collected_files = Vec::new();
     for i in 0..config.num_of_samples {
-        let timestamp = Utc::now().format("%Y%m%d_%H%M%S").to_string();
+        let chunk = i + 1;
+        let started_at = Utc::now();
+        let timestamp = started_at.format("%Y%m%d_%H%M%S").to_string();
         let remote_file = format!("pyspy_output_{}.json", timestamp);
 
-        println!(
-            "====> Starting py-spy chunk #{} for {} minutes",
-            i + 1,
-            config.duration_seconds
-        );
-        run_py_spy(&config, &remote_file)?;
+        log.emit(Event::ChunkStarted {
+            chunk,
+            remote_file: remote_file.clone(),
+        });
 
-        // Copy results to local machine
         let local_path = format!("{}/{}", config.local_output_dir, remote_file);
-        copy_results(&config, &remote_file, &local_path)?;
-        collected_files.push(local_path);
+        let result = run_py_spy(&config, &remote_file, log)
+            // Copy results to local machine
+            .and_then(|_| copy_results(&config, &remote_file, &local_path, log))
+            .and_then(|_| file_io::compress_file(&local_path, config.compression));
+        let local_path = match result {
+            Ok(local_path) => local_path,
+            Err(e) => {
+                log.emit(Event::ChunkFailed {
+                    chunk,
+                    error: e.to_string(),
+                });
+                return Err(e);
+            }
+        };
+        chunk_manifest::append_entry(
+            &config.local_output_dir,
+            &ManifestEntry {
+                path: local_path.clone(),
+                started_at: started_at.to_rfc3339(),
+                duration_seconds: config.duration_seconds,
+            },
+        )?;
+        log.emit(Event::ChunkCopied { chunk, local_path });
     }
     Ok(())
 }
 
-fn ensure_py_spy_installed(config: &ProfilerConfig) -> Result<(), Box<dyn std::error::Error>> {
-    println!(
+fn ensure_py_spy_installed(config: &ProfilerConfig, log: &EventLog) -> Result<()> {
+    log.info(&format!(
         "====> Checking if py-spy is installed in pod {}",
         config.pod_name
-    );
+    ));
 
     let py_spy_installed = is_py_spy_installed(config)?;
     if py_spy_installed {
-        println!("====> py-spy is installed in the container.");
+        log.emit(Event::InstallResult {
+            already_installed: true,
+            success: true,
+            error: None,
+        });
         return Ok(());
     }
 
-    println!("====> py-spy not found. Installing in container...");
-    install_py_spy(config)
+    log.info("====> py-spy not found. Installing in container...");
+    let result = install_py_spy(config, log);
+    log.emit(Event::InstallResult {
+        already_installed: false,
+        success: result.is_ok(),
+        error: result.as_ref().err().map(|e| e.to_string()),
+    });
+    result
 }
 
-fn install_py_spy(config: &ProfilerConfig) -> Result<(), Box<dyn std::error::Error>> {
+fn install_py_spy(config: &ProfilerConfig, log: &EventLog) -> Result<()> {
     let install_status = Command::new("kubectl")
-        .args(&[
+        .args([
             "exec",
             &config.pod_name,
             "-n",
@@ -97,17 +147,25 @@ fn install_py_spy(config: &ProfilerConfig) -> Result<(), Box<dyn std::error::Err
             "install",
             "py-spy",
         ])
-        .status()?;
+        .stdout(log.child_stdout())
+        .status()
+        .map_err(|e| Error::Install {
+            pod_name: config.pod_name.clone(),
+            reason: format!("could not run kubectl: {}", e),
+        })?;
 
     if !install_status.success() {
-        return Err("Failed to install py-spy".into());
+        return Err(Error::Install {
+            pod_name: config.pod_name.clone(),
+            reason: format!("pip install py-spy exited with {}", install_status),
+        });
     }
     Ok(())
 }
 
-fn is_py_spy_installed(config: &ProfilerConfig) -> Result<bool, std::io::Error> {
+fn is_py_spy_installed(config: &ProfilerConfig) -> Result<bool> {
     let check_output = Command::new("kubectl")
-        .args(&[
+        .args([
             "exec",
             &config.pod_name,
             "-n",
@@ -116,27 +174,29 @@ fn is_py_spy_installed(config: &ProfilerConfig) -> Result<bool, std::io::Error>
             "py-spy",
             "--version",
         ])
-        .output()?;
+        .output()
+        .map_err(|e| Error::Kubectl {
+            action: "exec".to_string(),
+            target: config.pod_name.clone(),
+            reason: e.to_string(),
+        })?;
     if check_output.status.code().unwrap_or(1) != 0 {
         return Ok(false);
     }
-    return Ok(true);
+    Ok(true)
 }
 
-fn run_py_spy(
-    config: &ProfilerConfig,
-    remote_filename: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
+fn run_py_spy(config: &ProfilerConfig, remote_filename: &str, log: &EventLog) -> Result<()> {
     // we assume that 1 is the target process
     let full_cmd = format!(
         "py-spy record --pid 1 --duration {} --output /tmp/{} --format=speedscope",
         &config.duration_seconds, remote_filename
     );
 
-    println!("====> Running py-spy in container: {}", full_cmd);
+    log.info(&format!("====> Running py-spy in container: {}", full_cmd));
 
     let status = Command::new("kubectl")
-        .args(&[
+        .args([
             "exec",
             &config.pod_name,
             "-n",
@@ -146,10 +206,16 @@ fn run_py_spy(
             "-c",
             &full_cmd,
         ])
-        .status()?;
+        .stdout(log.child_stdout())
+        .status()
+        .map_err(|e| record_error(config, remote_filename, e.to_string()))?;
 
     if !status.success() {
-        return Err("py-spy record command failed".into());
+        return Err(record_error(
+            config,
+            remote_filename,
+            format!("py-spy record exited with {}", status),
+        ));
     }
 
     Ok(())
@@ -159,20 +225,44 @@ fn copy_results(
     config: &ProfilerConfig,
     remote_filename: &str,
     local_path: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
+    log: &EventLog,
+) -> Result<()> {
     let remote_path = format!("/tmp/{}", remote_filename);
     let pod_resource = format!("{}/{}:{}", config.namespace, config.pod_name, remote_path);
 
-    println!("====> Copying results from container: {}", pod_resource);
+    log.info(&format!(
+        "====> Copying results from container: {}",
+        pod_resource
+    ));
 
     let status = Command::new("kubectl")
-        .args(&["cp", &pod_resource, local_path])
-        .status()?;
+        .args(["cp", &pod_resource, local_path])
+        .stdout(log.child_stdout())
+        .status()
+        .map_err(|e| Error::Kubectl {
+            action: "cp".to_string(),
+            target: pod_resource.clone(),
+            reason: e.to_string(),
+        })?;
 
     if !status.success() {
-        return Err("Failed to copy results from container".into());
+        return Err(Error::Kubectl {
+            action: "cp".to_string(),
+            target: pod_resource,
+            reason: format!("exited with {}", status),
+        });
     }
 
-    println!("Successfully copied to: {}", local_path);
     Ok(())
 }
+
+fn record_error(config: &ProfilerConfig, remote_filename: &str, reason: String) -> Error {
+    Error::Kubectl {
+        action: "exec".to_string(),
+        target: format!(
+            "{}/{}:/tmp/{}",
+            config.namespace, config.pod_name, remote_filename
+        ),
+        reason,
+    }
+}
@@ -1,10 +1,98 @@
-#[derive(serde::Serialize, Debug, Deserialize, Clone)]
+/// Fields not modelled by this crate, kept so files round-trip without losing them
+pub type Extra = serde_json::Map<String, serde_json::Value>;
+
+#[derive(serde::Serialize, Debug, Deserialize, Clone, Default)]
 pub struct Shared {
     pub frames: Vec<Frame>,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
-#[derive(serde::Serialize, Debug, Deserialize, Clone)]
-pub struct Profile {
+/// How many seconds one unit of `unit` is, `None` for units that are not durations
+pub fn seconds_per_unit(unit: &str) -> Option<f64> {
+    match unit {
+        "nanoseconds" => Some(1e-9),
+        "microseconds" => Some(1e-6),
+        "milliseconds" => Some(1e-3),
+        "seconds" => Some(1.0),
+        _ => None,
+    }
+}
+
+/// Factor to multiply values in `from` by to get values in `to`, `None` if one of them is
+/// not a duration. Equal units always convert, so `none` or `bytes` merge with themselves.
+pub fn unit_conversion(from: &str, to: &str) -> Option<f64> {
+    if from == to {
+        return Some(1.0);
+    }
+    Some(seconds_per_unit(from)? / seconds_per_unit(to)?)
+}
+
+/// A profile of one thread, speedscope files can hold either kind
+#[derive(serde::Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Profile {
+    Sampled(SampledProfile),
+    Evented(EventedProfile),
+}
+
+/// Every field of either kind of profile, so a profile is read in one pass wherever its
+/// `type` is
+#[derive(Deserialize)]
+struct ProfileFields {
+    r#type: String,
+    name: String,
+    unit: String,
+    #[serde(rename = "startValue")]
+    start_value: f64,
+    #[serde(rename = "endValue")]
+    end_value: f64,
+    samples: Option<Vec<Vec<i32>>>,
+    weights: Option<Vec<f64>>,
+    events: Option<Vec<FrameEvent>>,
+    #[serde(flatten)]
+    extra: Extra,
+}
+
+impl<'de> Deserialize<'de> for Profile {
+    /// Picks the kind of profile by `type` after reading its fields. A derived internally
+    /// tagged enum would first buffer the whole profile, samples included, to find the tag.
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let fields = ProfileFields::deserialize(deserializer)?;
+        match fields.r#type.as_str() {
+            "sampled" => Ok(Profile::Sampled(SampledProfile {
+                name: fields.name,
+                unit: fields.unit,
+                start_value: fields.start_value,
+                end_value: fields.end_value,
+                samples: fields
+                    .samples
+                    .ok_or_else(|| D::Error::missing_field("samples"))?,
+                weights: fields
+                    .weights
+                    .ok_or_else(|| D::Error::missing_field("weights"))?,
+                extra: fields.extra,
+            })),
+            "evented" => Ok(Profile::Evented(EventedProfile {
+                name: fields.name,
+                unit: fields.unit,
+                start_value: fields.start_value,
+                end_value: fields.end_value,
+                events: fields
+                    .events
+                    .ok_or_else(|| D::Error::missing_field("events"))?,
+                extra: fields.extra,
+            })),
+            other => Err(D::Error::unknown_variant(other, &["sampled", "evented"])),
+        }
+    }
+}
+
+/// Stacks sampled over time, each sample's duration is the matching weight
+#[derive(serde::Serialize, Debug, Deserialize, Clone, Default)]
+pub struct SampledProfile {
     pub name: String,
     pub unit: String,
     #[serde(rename = "startValue")]
@@ -13,63 +101,385 @@ pub struct Profile {
     pub end_value: f64,
     pub samples: Vec<Vec<i32>>,
     pub weights: Vec<f64>,
-    pub r#type: String,
-}
-
-impl Default for Profile {
-    fn default() -> Self {
-        Self {
-            name: String::new(),
-            unit: String::new(),
-            start_value: 0.0,
-            end_value: 0.0,
-            samples: Vec::new(),
-            weights: Vec::new(),
-            r#type: String::new(),
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+/// Frames opened and closed at points in time, as written by tracing profilers
+#[derive(serde::Serialize, Debug, Deserialize, Clone, Default)]
+pub struct EventedProfile {
+    pub name: String,
+    pub unit: String,
+    #[serde(rename = "startValue")]
+    pub start_value: f64,
+    #[serde(rename = "endValue")]
+    pub end_value: f64,
+    pub events: Vec<FrameEvent>,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+#[derive(serde::Serialize, Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum FrameEventType {
+    #[serde(rename = "O")]
+    Open,
+    #[serde(rename = "C")]
+    Close,
+}
+
+#[derive(serde::Serialize, Debug, Deserialize, Clone)]
+pub struct FrameEvent {
+    #[serde(rename = "type")]
+    pub r#type: FrameEventType,
+    pub at: f64,
+    pub frame: i32,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+impl Profile {
+    pub fn name(&self) -> &str {
+        match self {
+            Profile::Sampled(profile) => &profile.name,
+            Profile::Evented(profile) => &profile.name,
+        }
+    }
+
+    pub fn unit(&self) -> &str {
+        match self {
+            Profile::Sampled(profile) => &profile.unit,
+            Profile::Evented(profile) => &profile.unit,
+        }
+    }
+
+    /// The profile as a sampled profile, evented profiles are converted
+    pub fn to_sampled(&self) -> Result<Cow<'_, SampledProfile>, String> {
+        match self {
+            Profile::Sampled(profile) => Ok(Cow::Borrowed(profile)),
+            Profile::Evented(profile) => profile.to_sampled().map(Cow::Owned),
         }
     }
 }
 
+impl SampledProfile {
+    /// Checks samples and weights against a file with `num_of_frames` frames
+    fn validate(&self, num_of_frames: usize) -> Result<(), String> {
+        if self.samples.len() != self.weights.len() {
+            return Err(format!(
+                "profile {} has {} samples but {} weights",
+                self.name,
+                self.samples.len(),
+                self.weights.len()
+            ));
+        }
+        if let Some(weight) = self
+            .weights
+            .iter()
+            .find(|weight| !weight.is_finite() || **weight < 0.0)
+        {
+            return Err(format!(
+                "profile {} has invalid weight {}",
+                self.name, weight
+            ));
+        }
+        for sample in &self.samples {
+            if let Some(index) = sample
+                .iter()
+                .find(|index| **index < 0 || **index as usize >= num_of_frames)
+            {
+                return Err(format!(
+                    "profile {} references frame {} but only {} frames exist",
+                    self.name, index, num_of_frames
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl EventedProfile {
+    /// Walks the events in one pass, calling `interval` with the frames open between two
+    /// events and the length of that interval.
+    /// Fails on events out of order, frames not in `num_of_frames` when given, closes that
+    /// don't match the innermost open frame and frames still open at the end.
+    fn walk(
+        &self,
+        num_of_frames: Option<usize>,
+        mut interval: impl FnMut(&[i32], f64),
+    ) -> Result<(), String> {
+        let mut stack: Vec<i32> = Vec::new();
+        let mut last_at = self.start_value;
+        for event in &self.events {
+            if let Some(num_of_frames) = num_of_frames {
+                if event.frame < 0 || event.frame as usize >= num_of_frames {
+                    return Err(format!(
+                        "profile {} references frame {} but only {} frames exist",
+                        self.name, event.frame, num_of_frames
+                    ));
+                }
+            }
+            if event.at < last_at {
+                return Err(format!(
+                    "profile {} has an event at {} before the previous one at {}",
+                    self.name, event.at, last_at
+                ));
+            }
+            if event.at > last_at {
+                interval(&stack, event.at - last_at);
+                last_at = event.at;
+            }
+            match event.r#type {
+                FrameEventType::Open => stack.push(event.frame),
+                FrameEventType::Close => match stack.pop() {
+                    Some(open) if open == event.frame => {}
+                    Some(open) => {
+                        return Err(format!(
+                            "profile {} closes frame {} at {} while frame {} is open",
+                            self.name, event.frame, event.at, open
+                        ))
+                    }
+                    None => {
+                        return Err(format!(
+                            "profile {} closes frame {} at {} but no frame is open",
+                            self.name, event.frame, event.at
+                        ))
+                    }
+                },
+            }
+        }
+        if let Some(open) = stack.last() {
+            return Err(format!("profile {} never closes frame {}", self.name, open));
+        }
+        if self.end_value > last_at {
+            interval(&stack, self.end_value - last_at);
+        }
+        Ok(())
+    }
+
+    /// Checks the events against a file with `num_of_frames` frames without converting them
+    fn validate(&self, num_of_frames: usize) -> Result<(), String> {
+        self.walk(Some(num_of_frames), |_, _| {})
+    }
+
+    /// Converts to a sampled profile with one sample per interval between two events,
+    /// holding the frames open during it and weighted by its length.
+    /// Intervals where no frame is open become empty samples so the time axis is preserved.
+    pub fn to_sampled(&self) -> Result<SampledProfile, String> {
+        let mut samples = Vec::new();
+        let mut weights = Vec::new();
+        self.walk(None, |stack, weight| {
+            samples.push(stack.to_vec());
+            weights.push(weight);
+        })?;
+        Ok(SampledProfile {
+            name: self.name.clone(),
+            unit: self.unit.clone(),
+            start_value: self.start_value,
+            end_value: self.end_value,
+            samples,
+            weights,
+            extra: self.extra.clone(),
+        })
+    }
+}
+
+use chrono::{DateTime, Utc};
 use serde::Deserialize;
+use std::borrow::Cow;
+
+/// Field of files combined on a timeline holding the RFC 3339 wall-clock time that start
+/// and end values count from. Speedscope ignores fields it doesn't know.
+pub const SESSION_START: &str = "sessionStart";
 
+/// A speedscope file, modelled after https://www.speedscope.app/file-format-schema.json
 #[derive(serde::Serialize, Debug, Deserialize, Clone)]
 pub struct Speedscope {
     pub profiles: Vec<Profile>,
     pub shared: Shared,
     #[serde(rename = "$schema")]
     pub schema: String,
-    // null value
-    pub exporter: String,
-    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exporter: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Index into `profiles` of the profile to show first
+    #[serde(
+        rename = "activeProfileIndex",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub active_profile_index: Option<usize>,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
-#[derive(serde::Serialize, Debug, Deserialize, Clone)]
+impl Speedscope {
+    /// Wall-clock time a value of 0 stands for, only known for files combined on a timeline
+    pub fn session_start(&self) -> Option<DateTime<Utc>> {
+        let session_start = self.extra.get(SESSION_START)?.as_str()?;
+        DateTime::parse_from_rfc3339(session_start)
+            .ok()
+            .map(|session_start| session_start.with_timezone(&Utc))
+    }
+
+    /// How long the longest profile in the file lasts, `None` if a profile isn't measured in time
+    pub fn duration_seconds(&self) -> Option<f64> {
+        self.profiles.iter().try_fold(0.0, |longest: f64, profile| {
+            let (unit, start_value, end_value) = match profile {
+                Profile::Sampled(p) => (&p.unit, p.start_value, p.end_value),
+                Profile::Evented(p) => (&p.unit, p.start_value, p.end_value),
+            };
+            let seconds = (end_value - start_value) * seconds_per_unit(unit)?;
+            Some(longest.max(seconds))
+        })
+    }
+
+    /// Checks that the file is internally consistent, returning the first problem found
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(index) = self.active_profile_index {
+            if index >= self.profiles.len() {
+                return Err(format!(
+                    "activeProfileIndex {} is out of range for {} profiles",
+                    index,
+                    self.profiles.len()
+                ));
+            }
+        }
+        let num_of_frames = self.shared.frames.len();
+        for profile in &self.profiles {
+            match profile {
+                Profile::Sampled(sampled) => sampled.validate(num_of_frames)?,
+                Profile::Evented(evented) => evented.validate(num_of_frames)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(serde::Serialize, Debug, Deserialize, Clone, Default)]
 pub struct Frame {
     pub name: String,
-    pub file: String,
-    pub line: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub col: Option<u32>,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+/// Identity of a frame, frames with equal keys from different files are the same frame
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FrameKey {
+    pub name: String,
+    pub file: Option<String>,
+    pub line: Option<u32>,
     pub col: Option<u32>,
 }
 
-impl Default for Frame {
-    fn default() -> Self {
-        Self {
-            name: String::new(),
-            file: String::new(),
-            line: 0,
-            col: None,
+impl Frame {
+    pub fn key(&self) -> FrameKey {
+        FrameKey {
+            name: self.name.clone(),
+            file: self.file.clone(),
+            line: self.line,
+            col: self.col,
         }
     }
 }
 
-impl Frame {
-    pub fn hash(&self) -> String {
-        format!(
-            "{}:{}:{}:{}",
-            self.name,
-            self.file,
-            self.line,
-            self.col.unwrap_or(0)
-        )
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_speedscope_round_trips_all_fields() {
+        let json = serde_json::json!({
+            "$schema": "https://www.speedscope.app/file-format-schema.json",
+            "exporter": "speedscope@1.20.0",
+            "name": "request handler",
+            "activeProfileIndex": 1,
+            "x-custom": {"build": 42},
+            "shared": {
+                "frames": [
+                    {"name": "main", "file": "main.py", "line": 1, "col": 4, "x-module": "app"},
+                    {"name": "[native code]"}
+                ]
+            },
+            "profiles": [
+                {
+                    "type": "sampled", "name": "Thread 1", "unit": "seconds",
+                    "startValue": 0.0, "endValue": 1.5,
+                    "samples": [[0, 1]], "weights": [1.5],
+                    "x-thread-id": 7
+                },
+                {
+                    "type": "evented", "name": "Thread 2", "unit": "milliseconds",
+                    "startValue": 0.0, "endValue": 2.0,
+                    "events": [
+                        {"type": "O", "at": 0.0, "frame": 0, "x-note": "enter"},
+                        {"type": "C", "at": 2.0, "frame": 0}
+                    ]
+                }
+            ]
+        });
+
+        let speedscope: Speedscope = serde_json::from_value(json.clone()).unwrap();
+
+        assert_eq!(speedscope.active_profile_index, Some(1));
+        assert_eq!(speedscope.shared.frames[1].file, None);
+        assert_eq!(serde_json::to_value(&speedscope).unwrap(), json);
+    }
+
+    #[test]
+    fn test_minimal_file_from_other_exporter_parses() {
+        let json = r#"{
+            "$schema": "https://www.speedscope.app/file-format-schema.json",
+            "shared": {"frames": [{"name": "a"}, {"name": "b", "file": "b.js"}]},
+            "profiles": [{
+                "type": "sampled", "name": "cpu", "unit": "none",
+                "startValue": 0, "endValue": 2, "samples": [[0], [0, 1]], "weights": [1, 1]
+            }]
+        }"#;
+
+        let speedscope: Speedscope = serde_json::from_str(json).unwrap();
+
+        assert_eq!(speedscope.exporter, None);
+        assert_eq!(speedscope.name, None);
+        assert!(speedscope.validate().is_ok());
+    }
+
+    #[test]
+    fn test_profile_type_is_read_wherever_it_is() {
+        let json = r#"{
+            "name": "cpu", "unit": "none", "startValue": 0, "endValue": 1,
+            "samples": [[0]], "weights": [1], "x-thread-id": 7, "type": "sampled"
+        }"#;
+
+        let profile: Profile = serde_json::from_str(json).unwrap();
+
+        let Profile::Sampled(sampled) = profile else {
+            panic!("expected a sampled profile");
+        };
+        assert_eq!(sampled.samples, vec![vec![0]]);
+        assert_eq!(sampled.extra["x-thread-id"], 7);
+        assert!(serde_json::from_str::<Profile>(&json.replace("sampled", "evented")).is_err());
+        assert!(serde_json::from_str::<Profile>(&json.replace("sampled", "unknown")).is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_out_of_range_active_profile() {
+        let speedscope = Speedscope {
+            profiles: vec![],
+            shared: Shared::default(),
+            schema: String::new(),
+            exporter: None,
+            name: None,
+            active_profile_index: Some(0),
+            extra: Extra::new(),
+        };
+
+        assert!(speedscope.validate().is_err());
     }
 }
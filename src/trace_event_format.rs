@@ -0,0 +1,251 @@
+use crate::speedscope_format::{seconds_per_unit, Frame, Speedscope};
+use serde::Serialize;
+use std::io::{self, Write};
+
+/// All profiles of a speedscope file are threads of this process
+const PID: u32 = 1;
+
+/// One event of the Trace Event format read by Perfetto and chrome://tracing
+/// (docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU)
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TraceEvent {
+    pub name: String,
+    /// `X` for a complete slice, `M` for track metadata
+    pub ph: &'static str,
+    /// Start in microseconds
+    pub ts: f64,
+    /// Length in microseconds, only for slices
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dur: Option<f64>,
+    pub pid: u32,
+    pub tid: u32,
+    #[serde(skip_serializing_if = "serde_json::Map::is_empty")]
+    pub args: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A Trace Event file in the JSON object format
+#[derive(Serialize, Debug)]
+pub struct TraceFile {
+    #[serde(rename = "traceEvents")]
+    pub trace_events: Vec<TraceEvent>,
+    #[serde(rename = "displayTimeUnit")]
+    pub display_time_unit: &'static str,
+}
+
+fn metadata(name: &str, tid: u32, args: serde_json::Value) -> TraceEvent {
+    TraceEvent {
+        name: name.to_string(),
+        ph: "M",
+        ts: 0.0,
+        dur: None,
+        pid: PID,
+        tid,
+        args: match args {
+            serde_json::Value::Object(args) => args,
+            _ => unreachable!("metadata args are objects"),
+        },
+    }
+}
+
+fn slice(frame: &Frame, ts: f64, tid: u32) -> TraceEvent {
+    let mut args = serde_json::Map::new();
+    if let Some(file) = &frame.file {
+        args.insert("file".to_string(), file.clone().into());
+    }
+    if let Some(line) = frame.line {
+        args.insert("line".to_string(), line.into());
+    }
+    TraceEvent {
+        name: frame.name.clone(),
+        ph: "X",
+        ts,
+        dur: Some(0.0),
+        pid: PID,
+        tid,
+        args,
+    }
+}
+
+/// Ends the open slices from `depth` on at `at`
+fn close_slices(open: &mut Vec<(i32, usize)>, events: &mut [TraceEvent], depth: usize, at: f64) {
+    for (_, event) in open.drain(depth..) {
+        events[event].dur = Some(at - events[event].ts);
+    }
+}
+
+/// Builds a trace with one track per profile from a valid speedscope file.
+///
+/// Samples are laid end to end from the profile's start value, each lasting its weight.
+/// Timestamps are relative to the start values, not wall-clock times, which speedscope
+/// files don't record.
+/// A frame that stays at the same depth over consecutive samples becomes one slice,
+/// so the tracks show how long each call ran. Empty samples close every slice.
+/// All profiles must be measured in time.
+pub fn to_trace_events(speedscope: &Speedscope) -> Result<TraceFile, String> {
+    let process_name = speedscope.name.as_deref().unwrap_or("python");
+    let mut events = vec![metadata(
+        "process_name",
+        0,
+        serde_json::json!({ "name": process_name }),
+    )];
+    for (index, profile) in speedscope.profiles.iter().enumerate() {
+        let profile = profile.to_sampled()?;
+        let micros = seconds_per_unit(&profile.unit)
+            .map(|seconds| seconds * 1e6)
+            .ok_or_else(|| {
+                format!(
+                    "profile {} is in {:?}, which has no time axis to trace",
+                    profile.name, profile.unit
+                )
+            })?;
+        let tid = index as u32 + 1;
+        events.push(metadata(
+            "thread_name",
+            tid,
+            serde_json::json!({ "name": profile.name }),
+        ));
+        events.push(metadata(
+            "thread_sort_index",
+            tid,
+            serde_json::json!({ "sort_index": index }),
+        ));
+
+        // frame index and event index of every open slice, outermost first
+        let mut open: Vec<(i32, usize)> = Vec::new();
+        let mut at = profile.start_value;
+        for (sample, weight) in profile.samples.iter().zip(&profile.weights) {
+            let common = open
+                .iter()
+                .zip(sample)
+                .take_while(|((open_frame, _), frame)| open_frame == *frame)
+                .count();
+            close_slices(&mut open, &mut events, common, at * micros);
+            for frame in &sample[common..] {
+                let frame_data = &speedscope.shared.frames[*frame as usize];
+                events.push(slice(frame_data, at * micros, tid));
+                open.push((*frame, events.len() - 1));
+            }
+            at += weight;
+        }
+        close_slices(&mut open, &mut events, 0, at * micros);
+    }
+    Ok(TraceFile {
+        trace_events: events,
+        display_time_unit: "ms",
+    })
+}
+
+/// Writes a valid speedscope file as Trace Event JSON, see `to_trace_events`
+pub fn write_trace_events(speedscope: &Speedscope, out: &mut impl Write) -> io::Result<()> {
+    let trace = to_trace_events(speedscope).map_err(io::Error::other)?;
+    serde_json::to_writer(out, &trace).map_err(io::Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::speedscope_format::{Extra, Profile, SampledProfile, Shared};
+
+    fn frame(name: &str, line: u32) -> Frame {
+        Frame {
+            name: name.to_string(),
+            file: Some("app.py".to_string()),
+            line: Some(line),
+            ..Frame::default()
+        }
+    }
+
+    fn input(unit: &str, samples: Vec<Vec<i32>>, weights: Vec<f64>) -> Speedscope {
+        Speedscope {
+            profiles: vec![Profile::Sampled(SampledProfile {
+                name: "MainThread".to_string(),
+                unit: unit.to_string(),
+                start_value: 1.0,
+                end_value: 1.0 + weights.iter().sum::<f64>(),
+                samples,
+                weights,
+                ..SampledProfile::default()
+            })],
+            shared: Shared {
+                frames: vec![frame("main", 1), frame("handle", 5), frame("query", 9)],
+                extra: Extra::new(),
+            },
+            schema: String::new(),
+            exporter: None,
+            name: None,
+            active_profile_index: None,
+            extra: Extra::new(),
+        }
+    }
+
+    fn slices(trace: &TraceFile) -> Vec<(&str, f64, f64)> {
+        trace
+            .trace_events
+            .iter()
+            .filter(|event| event.ph == "X")
+            .map(|event| (event.name.as_str(), event.ts, event.dur.unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_consecutive_samples_become_one_slice() {
+        let trace = to_trace_events(&input(
+            "milliseconds",
+            vec![vec![0, 1], vec![0, 1, 2], vec![0, 1], vec![0]],
+            vec![1.0, 2.0, 1.0, 0.5],
+        ))
+        .unwrap();
+
+        assert_eq!(
+            slices(&trace),
+            vec![
+                ("main", 1000.0, 4500.0),
+                ("handle", 1000.0, 4000.0),
+                ("query", 2000.0, 2000.0),
+            ]
+        );
+        let query = trace
+            .trace_events
+            .iter()
+            .find(|e| e.name == "query")
+            .unwrap();
+        assert_eq!(query.args["file"], "app.py");
+        assert_eq!(query.args["line"], 9);
+        assert_eq!(query.tid, 1);
+    }
+
+    #[test]
+    fn test_empty_samples_close_slices() {
+        let trace = to_trace_events(&input(
+            "seconds",
+            vec![vec![0], vec![], vec![0]],
+            vec![1.0, 1.0, 1.0],
+        ))
+        .unwrap();
+
+        assert_eq!(slices(&trace), vec![("main", 1e6, 1e6), ("main", 3e6, 1e6)]);
+    }
+
+    #[test]
+    fn test_tracks_are_named_after_profiles() {
+        let trace = to_trace_events(&input("seconds", vec![vec![0]], vec![1.0])).unwrap();
+
+        let json = serde_json::to_value(&trace).unwrap();
+        let thread_name = json["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|event| event["name"] == "thread_name")
+            .unwrap();
+        assert_eq!(thread_name["args"]["name"], "MainThread");
+        assert_eq!(thread_name["ph"], "M");
+        assert_eq!(json["displayTimeUnit"], "ms");
+    }
+
+    #[test]
+    fn test_profiles_without_time_unit_are_rejected() {
+        let result = to_trace_events(&input("none", vec![vec![0]], vec![1.0]));
+
+        assert!(result.unwrap_err().contains("\"none\""));
+    }
+}